@@ -0,0 +1,99 @@
+use std::io;
+
+use rotor::{Config, Loop, Machine};
+
+
+/// Builder of `rotor::Config` tuned for tests
+///
+/// Capacities are small by default, so tests can hit the limits of the
+/// timer, the notification queue and the state machine slab with just
+/// a few machines.
+#[derive(Debug, Clone)]
+pub struct TestConfig {
+    timer_capacity: usize,
+    notify_capacity: usize,
+    slab_capacity: usize,
+}
+
+impl TestConfig {
+    /// Create a config with capacity of 16 for everything
+    pub fn new() -> TestConfig {
+        TestConfig {
+            timer_capacity: 16,
+            notify_capacity: 16,
+            slab_capacity: 16,
+        }
+    }
+    /// Maximum number of timeouts (rounded up to a power of two by mio)
+    pub fn timer_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.timer_capacity = capacity;
+        self
+    }
+    /// Maximum number of wakeups queued in the loop
+    pub fn notify_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.notify_capacity = capacity;
+        self
+    }
+    /// Maximum number of state machines
+    pub fn slab_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.slab_capacity = capacity;
+        self
+    }
+    /// Build the configuration
+    pub fn config(&self) -> Config {
+        let mut cfg = Config::new();
+        cfg.mio().timer_capacity(self.timer_capacity);
+        cfg.mio().notify_capacity(self.notify_capacity);
+        cfg.slab_capacity(self.slab_capacity);
+        cfg
+    }
+    /// Create a real loop with the configuration
+    pub fn create_loop<M: Machine>(&self) -> io::Result<Loop<M>> {
+        Loop::new(&self.config())
+    }
+}
+
+impl Default for TestConfig {
+    fn default() -> TestConfig {
+        TestConfig::new()
+    }
+}
+
+#[cfg(test)]
+mod self_test {
+    use rotor::{Machine, EventSet, Scope, Response};
+    use rotor::void::{unreachable, Void};
+    use super::TestConfig;
+
+    struct Idle;
+
+    impl Machine for Idle {
+        type Context = ();
+        type Seed = Void;
+        fn create(seed: Void, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            unreachable(seed)
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<()>)
+            -> Response<Self, Void>
+        {
+            unimplemented!();
+        }
+        fn spawned(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            unimplemented!();
+        }
+        fn timeout(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            unimplemented!();
+        }
+        fn wakeup(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            unimplemented!();
+        }
+    }
+
+    #[test]
+    fn slab_capacity() {
+        let mut lp = TestConfig::new().slab_capacity(1)
+            .create_loop::<Idle>().unwrap();
+        lp.add_machine_with(|_| Response::ok(Idle)).unwrap();
+        assert!(lp.add_machine_with(|_| Response::ok(Idle)).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use std::ops::{Deref, DerefMut};
+
+
+/// Context of a mock loop for composed state machines
+///
+/// Children of a composed machine often require different contexts. The
+/// wrapper holds a context for each of them, and `MockLoop::scope_first()`
+/// and `MockLoop::scope_second()` give out scopes with the respective one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComposedCtx<A, B>(pub A, pub B);
+
+impl<A, B> ComposedCtx<A, B> {
+    /// Context of the first child
+    pub fn first(&mut self) -> &mut A {
+        &mut self.0
+    }
+    /// Context of the second child
+    pub fn second(&mut self) -> &mut B {
+        &mut self.1
+    }
+}
+
+/// Context of a mock loop for code which accesses it as a trait object
+///
+/// Use `MockLoop::with_dyn_scope()` to get a scope with `&mut T` context,
+/// where `T` is usually a `dyn Trait` implemented by a test-only context.
+pub struct DynContext<T: ?Sized>(Box<T>);
+
+impl<T: ?Sized> DynContext<T> {
+    /// Wrap a boxed context
+    pub fn new(ctx: Box<T>) -> DynContext<T> {
+        DynContext(ctx)
+    }
+}
+
+impl<T: ?Sized> Deref for DynContext<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: ?Sized> DerefMut for DynContext<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: ?Sized> AsMut<T> for DynContext<T> {
+    fn as_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
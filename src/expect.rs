@@ -0,0 +1,221 @@
+use std::fmt::Write;
+
+use rotor::{EventSet, PollOpt};
+
+use scope::Operation;
+
+
+/// An operation expected to be done through the scope
+///
+/// Unlike `Operation` it has no token, so it matches operations done from
+/// any scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    Register(EventSet, PollOpt),
+    Reregister(EventSet, PollOpt),
+    Deregister,
+    ClearTimeout,
+    Shutdown,
+}
+
+impl Expected {
+    /// Returns true if operation is the expected one
+    pub fn matches(&self, op: &Operation) -> bool {
+        match (*self, op) {
+            (Expected::Register(e, o), &Operation::Register(_, ae, ao))
+            => e == ae && o == ao,
+            (Expected::Reregister(e, o), &Operation::Reregister(_, ae, ao))
+            => e == ae && o == ao,
+            (Expected::Deregister, &Operation::Deregister(_)) => true,
+            (Expected::ClearTimeout, &Operation::ClearTimeout(_)) => true,
+            (Expected::Shutdown, &Operation::Shutdown(..)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Checks that operations match expectations one by one
+///
+/// Returns a side by side listing with mismatched lines marked if not.
+pub fn check_ordered(expected: &[Expected], actual: &[Operation])
+    -> Result<(), String>
+{
+    let ok = expected.len() == actual.len() &&
+        expected.iter().zip(actual).all(|(e, a)| e.matches(a));
+    if ok {
+        return Ok(());
+    }
+    let mut buf = String::from("operations don't match expectations:\n");
+    for i in 0..expected.len().max(actual.len()) {
+        let exp = expected.get(i).map(|e| format!("{:?}", e))
+            .unwrap_or_else(|| String::from("<nothing>"));
+        let act = actual.get(i).map(|a| format!("{:?}", a))
+            .unwrap_or_else(|| String::from("<nothing>"));
+        let same = match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) => e.matches(a),
+            _ => false,
+        };
+        writeln!(&mut buf, "{} {:3}: expected {:<40} got {}",
+            if same { " " } else { "!" }, i, exp, act).unwrap();
+    }
+    Err(buf)
+}
+
+/// Checks that every expectation is matched by an operation in any order
+///
+/// Unless `allow_extra` is true, there must be no other operations.
+/// Returns unmatched expectations and operations if not.
+pub fn check_unordered(expected: &[Expected], actual: &[Operation],
+    allow_extra: bool)
+    -> Result<(), String>
+{
+    let mut left: Vec<&Operation> = actual.iter().collect();
+    let mut missing = Vec::new();
+    for e in expected {
+        match left.iter().position(|a| e.matches(a)) {
+            Some(idx) => {
+                left.remove(idx);
+            }
+            None => missing.push(e),
+        }
+    }
+    if missing.is_empty() && (allow_extra || left.is_empty()) {
+        return Ok(());
+    }
+    let mut buf = String::from("operations don't match expectations:\n");
+    for e in missing {
+        writeln!(&mut buf, "  missing {:?}", e).unwrap();
+    }
+    if !allow_extra {
+        for a in left {
+            writeln!(&mut buf, "  unexpected {:?}", a).unwrap();
+        }
+    }
+    Err(buf)
+}
+
+/// A cursor over recorded operations (see `MockLoop::cursor()`)
+///
+/// Each assertion panics with the number of the operation which doesn't
+/// match, so failures point to the exact step.
+pub struct OpCursor<'a> {
+    ops: &'a [Operation],
+    pos: usize,
+}
+
+impl<'a> OpCursor<'a> {
+    /// Create a cursor at the first operation
+    pub fn new(ops: &'a [Operation]) -> OpCursor<'a> {
+        OpCursor { ops, pos: 0 }
+    }
+    /// Check that the next operation is `op` and move past it
+    pub fn assert_next(&mut self, op: Operation) -> &mut Self {
+        match self.ops.get(self.pos) {
+            Some(actual) if *actual == op => {}
+            Some(actual) => panic!("operation #{} is {:?}, expected {:?}",
+                self.pos, actual, op),
+            None => panic!("operation #{} is missing, expected {:?}",
+                self.pos, op),
+        }
+        self.pos += 1;
+        self
+    }
+    /// Skip operations while the predicate is true
+    pub fn skip_while<P>(&mut self, predicate: P) -> &mut Self
+        where P: Fn(&Operation) -> bool
+    {
+        while self.ops.get(self.pos).map(&predicate).unwrap_or(false) {
+            self.pos += 1;
+        }
+        self
+    }
+    /// Check that there are no more operations
+    pub fn assert_end(&self) {
+        if let Some(op) = self.ops.get(self.pos) {
+            panic!("operation #{} is {:?}, expected no more operations",
+                self.pos, op);
+        }
+    }
+}
+
+#[cfg(test)]
+mod self_test {
+    use rotor::{EventSet, PollOpt, Time};
+    use rotor::mio::Token;
+    use scope::Operation;
+    use super::{Expected, OpCursor, check_ordered, check_unordered};
+
+    #[test]
+    fn ordered() {
+        let ops = vec![
+            Operation::Register(Token(1), EventSet::readable(),
+                                PollOpt::edge()),
+            Operation::Deregister(Token(1)),
+        ];
+        check_ordered(&[
+            Expected::Register(EventSet::readable(), PollOpt::edge()),
+            Expected::Deregister,
+        ], &ops).unwrap();
+        let err = check_ordered(&[
+            Expected::Register(EventSet::readable(), PollOpt::edge()),
+            Expected::Reregister(EventSet::writable(), PollOpt::edge()),
+            Expected::Deregister,
+        ], &ops).unwrap_err();
+        let lines: Vec<_> = err.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("    0:"));
+        assert!(lines[2].starts_with("!   1:"));
+        assert!(lines[3].ends_with("got <nothing>"));
+    }
+
+    #[test]
+    fn unordered() {
+        let ops = vec![
+            Operation::Deregister(Token(2)),
+            Operation::Register(Token(1), EventSet::readable(),
+                                PollOpt::edge()),
+            Operation::Shutdown(Token(1), Time::zero(), None),
+        ];
+        let expected = [
+            Expected::Register(EventSet::readable(), PollOpt::edge()),
+            Expected::Deregister,
+        ];
+        check_unordered(&expected, &ops, true).unwrap();
+        let err = check_unordered(&expected, &ops, false).unwrap_err();
+        assert!(err.ends_with(
+            "  unexpected Shutdown(Token(1), Time(1), None)\n"));
+        let err = check_unordered(&[Expected::Deregister; 2], &ops, true)
+            .unwrap_err();
+        assert!(err.ends_with("  missing Deregister\n"));
+    }
+
+    #[test]
+    fn cursor() {
+        let ops = vec![
+            Operation::Register(Token(1), EventSet::readable(),
+                                PollOpt::edge()),
+            Operation::ClearTimeout(Token(2)),
+            Operation::ClearTimeout(Token(1)),
+            Operation::Deregister(Token(1)),
+        ];
+        OpCursor::new(&ops)
+            .assert_next(Operation::Register(Token(1), EventSet::readable(),
+                                             PollOpt::edge()))
+            .skip_while(|op| matches!(*op, Operation::ClearTimeout(_)))
+            .assert_next(Operation::Deregister(Token(1)))
+            .assert_end();
+    }
+
+    #[test]
+    #[should_panic(expected="operation #1 is ClearTimeout(Token(2)), \
+                             expected Deregister(Token(1))")]
+    fn cursor_mismatch() {
+        let ops = vec![
+            Operation::Deregister(Token(1)),
+            Operation::ClearTimeout(Token(2)),
+        ];
+        OpCursor::new(&ops)
+            .assert_next(Operation::Deregister(Token(1)))
+            .assert_next(Operation::Deregister(Token(1)));
+    }
+}
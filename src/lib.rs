@@ -3,6 +3,24 @@ extern crate rotor_stream;
 
 mod stream;
 mod scope;
+mod mock_scope;
+mod expect;
+mod config;
+mod context;
+mod pcap;
+mod split;
+mod listener;
+mod udp;
+mod seq;
 
-pub use stream::MemIo;
-pub use scope::{MockLoop, Operation};
+pub use stream::{MemIo, MemConnector, IoStep, IoStats, IoEvent};
+pub use stream::{SocketOptions, Checkpoint, OutputMark};
+pub use split::{MemReader, MemWriter, AppSide, TestSide};
+pub use listener::MemListener;
+pub use udp::MockUdp;
+pub use scope::{MockLoop, Operation, PendingTimeout, ShutdownReason};
+pub use scope::LoopEvent;
+pub use mock_scope::MockScope;
+pub use expect::{Expected, OpCursor};
+pub use config::TestConfig;
+pub use context::{ComposedCtx, DynContext};
@@ -0,0 +1,152 @@
+use std::io;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rotor::mio;
+
+use stream::MemIo;
+use scope::Registration;
+
+
+/// In memory listening socket
+///
+/// Implements `mio::TryAccept` so it can be used with `rotor_stream::Accept`
+/// machine (the accepted socket is `MemIo`). Connections are enqueued by
+/// the test with `enqueue_connection`, when there are none, accept returns
+/// `None` (i.e. would block).
+///
+/// Like `MemIo` it implements `mio::Evented` by recording the registration
+/// (see `registration()`). When registered through `MockLoop` the token is
+/// marked readable while there are pending connections. Clone the listener
+/// to keep a handle for the test.
+#[derive(Clone, Debug)]
+pub struct MemListener(Arc<Mutex<Listener>>);
+
+#[derive(Debug)]
+struct Listener {
+    pending: VecDeque<MemIo>,
+    registration: Registration,
+}
+
+impl MemListener {
+    /// Create a listener with no pending connections
+    pub fn new() -> MemListener {
+        MemListener(Arc::new(Mutex::new(Listener {
+            pending: VecDeque::new(),
+            registration: Registration::default(),
+        })))
+    }
+    /// Add a connection which will be returned by the next `accept()`
+    ///
+    /// Keep a clone of the stream to feed the input and check the output.
+    pub fn enqueue_connection(&self, stream: MemIo) {
+        let mut lst = self.lock();
+        lst.pending.push_back(stream);
+        lst.signal_readable();
+    }
+    /// Number of connections which are not accepted yet
+    pub fn pending_connections(&self) -> usize {
+        self.lock().pending.len()
+    }
+    /// Returns token, interest and poll options the listener is registered
+    /// with in the loop, if any
+    pub fn registration(&self)
+        -> Option<(mio::Token, mio::EventSet, mio::PollOpt)>
+    {
+        self.lock().registration.get()
+    }
+    fn lock<'a>(&'a self) -> MutexGuard<'a, Listener> {
+        self.0.lock().expect("Poisoned MemListener")
+    }
+}
+
+impl Default for MemListener {
+    fn default() -> MemListener {
+        MemListener::new()
+    }
+}
+
+impl Listener {
+    fn signal_readable(&self) {
+        if !self.pending.is_empty() {
+            self.registration.signal(mio::EventSet::readable());
+        }
+    }
+}
+
+impl mio::TryAccept for MemListener {
+    type Output = MemIo;
+    fn accept(&self) -> io::Result<Option<MemIo>> {
+        Ok(self.lock().pending.pop_front())
+    }
+}
+
+impl mio::Evented for MemListener {
+    fn register(&self, _selector: &mut mio::Selector,
+        token: mio::Token, interest: mio::EventSet, opts: mio::PollOpt)
+        -> io::Result<()>
+    {
+        let mut lst = self.lock();
+        lst.registration.register(token, interest, opts)?;
+        lst.signal_readable();
+        Ok(())
+    }
+    fn reregister(&self, _selector: &mut mio::Selector, token: mio::Token,
+        interest: mio::EventSet, opts: mio::PollOpt) -> io::Result<()>
+    {
+        let mut lst = self.lock();
+        lst.registration.reregister(token, interest, opts)?;
+        lst.signal_readable();
+        Ok(())
+    }
+    fn deregister(&self, _selector: &mut mio::Selector) -> io::Result<()>
+    {
+        self.lock().registration.deregister()
+    }
+}
+
+#[cfg(test)]
+mod self_test {
+    use std::io::Read;
+    use rotor::{EventSet, PollOpt};
+    use rotor::mio::{Token, TryAccept};
+    use stream::MemIo;
+    use scope::MockLoop;
+    use super::MemListener;
+
+    #[test]
+    fn accept() {
+        let lst = MemListener::new();
+        assert!(lst.accept().unwrap().is_none());
+        let mut conn = MemIo::new();
+        conn.push_bytes("hello");
+        lst.enqueue_connection(conn);
+        assert_eq!(lst.pending_connections(), 1);
+        let mut sock = lst.accept().unwrap().unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(sock.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert!(lst.accept().unwrap().is_none());
+    }
+
+    #[test]
+    fn register() {
+        let mut factory = MockLoop::new(());
+        let lst = MemListener::new();
+        lst.enqueue_connection(MemIo::new());
+        factory.scope(2).register(&lst, EventSet::readable(),
+            PollOpt::level()).unwrap();
+        assert_eq!(lst.registration(),
+            Some((Token(2), EventSet::readable(), PollOpt::level())));
+        assert!(factory.scope(2).register(&lst, EventSet::readable(),
+            PollOpt::level()).is_err());
+        assert_eq!(factory.take_ready_tokens(),
+                   vec![(Token(2), EventSet::readable())]);
+        lst.enqueue_connection(MemIo::new());
+        assert_eq!(factory.take_ready_tokens(),
+                   vec![(Token(2), EventSet::readable())]);
+        factory.scope(2).deregister(&lst).unwrap();
+        assert_eq!(lst.registration(), None);
+        assert!(factory.scope(2).deregister(&lst).is_err());
+    }
+}
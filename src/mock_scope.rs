@@ -0,0 +1,159 @@
+use std::io;
+
+use rotor::mio;
+use rotor::{GenericScope, Notifier, Time, PollOpt, EventSet};
+use rotor::_LoopApi;
+
+use scope::Operation;
+
+
+/// A lightweight scope for code written against `GenericScope`
+///
+/// Unlike `MockLoop` it has no context and doesn't create a `mio::EventLoop`.
+/// Registrations are only recorded as operations and not passed to the
+/// socket.
+///
+/// Timeouts and notifiers are not supported: rotor gives no way to create
+/// a `Timeout` or a `Notifier` without a real event loop, so `timeout_ms()`
+/// and `notifier()` panic. Use `MockLoop` for code which needs them.
+pub struct MockScope {
+    token: mio::Token,
+    now: Time,
+    operations: Vec<Operation>,
+    shutdown: bool,
+}
+
+impl MockScope {
+    /// Create a scope for the token, time is `Time::zero()`
+    pub fn new(token: usize) -> MockScope {
+        MockScope {
+            token: mio::Token(token),
+            now: Time::zero(),
+            operations: Vec::new(),
+            shutdown: false,
+        }
+    }
+    /// Set the time which `now()` returns
+    pub fn set_time(&mut self, now: Time) {
+        self.now = now;
+    }
+    /// Shutdown the event loop (only recorded)
+    pub fn shutdown_loop(&mut self) {
+        _LoopApi::shutdown(self)
+    }
+    /// Operations done through the scope so far
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+    /// Same as `operations()` but also clears the list
+    pub fn take_operations(&mut self) -> Vec<Operation> {
+        self.operations.drain(..).collect()
+    }
+    /// Returns true if `shutdown_loop()` was called
+    pub fn is_shutdown_requested(&self) -> bool {
+        self.shutdown
+    }
+}
+
+impl _LoopApi for MockScope {
+    fn register(&mut self, _io: &dyn mio::Evented, token: mio::Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        self.operations.push(Operation::Register(token, interest, opt));
+        Ok(())
+    }
+    fn reregister(&mut self, _io: &dyn mio::Evented, token: mio::Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        self.operations.push(Operation::Reregister(token, interest, opt));
+        Ok(())
+    }
+    fn deregister(&mut self, _io: &dyn mio::Evented) -> io::Result<()> {
+        self.operations.push(Operation::Deregister(self.token));
+        Ok(())
+    }
+    fn timeout_ms(&mut self, _token: mio::Token, _delay: u64)
+        -> Result<mio::Timeout, mio::TimerError>
+    {
+        panic!("MockScope doesn't support timeouts, use MockLoop");
+    }
+    fn clear_timeout(&mut self, _timeout: mio::Timeout) -> bool {
+        // the timeout can't belong to this scope
+        self.operations.push(Operation::ClearTimeout(self.token));
+        false
+    }
+    fn shutdown(&mut self) {
+        self.operations.push(Operation::Shutdown(self.token, self.now, None));
+        self.shutdown = true;
+    }
+}
+
+impl GenericScope for MockScope {
+    fn register(&mut self, io: &dyn mio::Evented, interest: EventSet,
+        opt: PollOpt) -> io::Result<()>
+    {
+        let token = self.token;
+        _LoopApi::register(self, io, token, interest, opt)
+    }
+    fn reregister(&mut self, io: &dyn mio::Evented, interest: EventSet,
+        opt: PollOpt) -> io::Result<()>
+    {
+        let token = self.token;
+        _LoopApi::reregister(self, io, token, interest, opt)
+    }
+    fn deregister(&mut self, io: &dyn mio::Evented) -> io::Result<()> {
+        _LoopApi::deregister(self, io)
+    }
+    fn timeout_ms(&mut self, delay: u64)
+        -> Result<mio::Timeout, mio::TimerError>
+    {
+        let token = self.token;
+        _LoopApi::timeout_ms(self, token, delay)
+    }
+    fn clear_timeout(&mut self, timeout: mio::Timeout) -> bool {
+        _LoopApi::clear_timeout(self, timeout)
+    }
+    fn notifier(&mut self) -> Notifier {
+        panic!("MockScope doesn't support notifiers, use MockLoop");
+    }
+    fn now(&self) -> Time {
+        self.now
+    }
+}
+
+#[cfg(test)]
+mod self_test {
+    use rotor::{GenericScope, EventSet, PollOpt};
+    use rotor::mio::Token;
+    use stream::MemIo;
+    use scope::Operation;
+    use super::MockScope;
+
+    fn start<S: GenericScope>(scope: &mut S, io: &MemIo) {
+        scope.register(io, EventSet::readable(), PollOpt::level()).unwrap();
+    }
+
+    #[test]
+    fn generic_scope() {
+        let mut scope = MockScope::new(3);
+        start(&mut scope, &MemIo::new());
+        assert_eq!(scope.take_operations(), vec![
+            Operation::Register(Token(3), EventSet::readable(),
+                                PollOpt::level()),
+        ]);
+        scope.shutdown_loop();
+        assert!(scope.is_shutdown_requested());
+    }
+
+    #[test]
+    #[should_panic(expected="MockScope doesn't support timeouts")]
+    fn timeouts_unsupported() {
+        MockScope::new(3).timeout_ms(100).ok();
+    }
+
+    #[test]
+    #[should_panic(expected="MockScope doesn't support notifiers")]
+    fn notifiers_unsupported() {
+        MockScope::new(3).notifier();
+    }
+}
@@ -0,0 +1,463 @@
+use std::io;
+use std::cmp::max;
+use std::time::Duration;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+
+
+/// A TCP segment with non-empty payload read from a pcap capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpSegment {
+    /// Capture time (since the unix epoch)
+    pub time: Duration,
+    /// Address of the sender
+    pub source: SocketAddr,
+    /// Address of the receiver
+    pub destination: SocketAddr,
+    /// Payload of the segment
+    pub payload: Vec<u8>,
+}
+
+// Reassembly state of one direction of a TCP connection
+struct Flow {
+    source: SocketAddr,
+    destination: SocketAddr,
+    // Next sequence number expected
+    next: u32,
+    // Segments which arrived ahead of `next`, with their capture time
+    ahead: Vec<(u32, Duration, Vec<u8>)>,
+}
+
+enum Endian {
+    Little,
+    Big,
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn be16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+fn be32(data: &[u8]) -> u32 {
+    (be16(data) as u32) << 16 | be16(&data[2..]) as u32
+}
+
+impl Endian {
+    fn u32(&self, data: &[u8]) -> u32 {
+        match *self {
+            Endian::Big => be32(data),
+            Endian::Little => (data[3] as u32) << 24 | (data[2] as u32) << 16
+                            | (data[1] as u32) << 8 | data[0] as u32,
+        }
+    }
+}
+
+impl Flow {
+    // Adds the segment, returns segments which continue the stream
+    fn reassemble(&mut self, seq: u32, time: Duration, payload: &[u8])
+        -> Vec<(Duration, Vec<u8>)>
+    {
+        self.ahead.push((seq, time, payload.to_vec()));
+        self.take_ready(time)
+    }
+    // Removes segments which start at or before `next`, trims the data
+    // seen already
+    fn take_ready(&mut self, time: Duration) -> Vec<(Duration, Vec<u8>)> {
+        let mut result = Vec::new();
+        loop {
+            let next = self.next;
+            let idx = self.ahead.iter()
+                .position(|&(seq, _, _)| next.wrapping_sub(seq) < 1 << 31);
+            let (seq, seg_time, data) = match idx {
+                Some(idx) => self.ahead.remove(idx),
+                None => break,
+            };
+            // Wrapping distance of the segment start behind `next`
+            let behind = next.wrapping_sub(seq) as usize;
+            if behind < data.len() {
+                self.next = seq.wrapping_add(data.len() as u32);
+                // Held data becomes readable only when the gap is filled
+                result.push((max(seg_time, time), data[behind..].to_vec()));
+            }
+        }
+        result
+    }
+}
+
+/// Parse pcap file and return TCP segments in order they were captured
+///
+/// Only classic pcap format (not pcapng) is supported, with ethernet, linux
+/// cooked, loopback and raw IP link types. Segments which have no payload
+/// or are retransmissions of already seen data are skipped. Packets which
+/// can't be parsed (non-TCP, fragmented IP) are skipped too.
+///
+/// Segments which arrive ahead of a gap in sequence numbers are held until
+/// the gap is filled, and are returned after the segment which filled it.
+/// If the gap is never filled (i.e. a packet is missing from the capture),
+/// they are returned at the end, in the order of sequence numbers.
+pub fn tcp_segments(data: &[u8]) -> io::Result<Vec<TcpSegment>> {
+    if data.len() < 24 {
+        return Err(invalid("pcap file is too short"));
+    }
+    let (endian, nanos) = match be32(data) {
+        0xa1b2c3d4 => (Endian::Big, false),
+        0xd4c3b2a1 => (Endian::Little, false),
+        0xa1b23c4d => (Endian::Big, true),
+        0x4d3cb2a1 => (Endian::Little, true),
+        _ => return Err(invalid("bad pcap magic (pcapng is not supported)")),
+    };
+    let link = endian.u32(&data[20..]);
+    let mut result = Vec::new();
+    let mut flows: Vec<Flow> = Vec::new();
+    let mut pos = 24;
+    while pos < data.len() {
+        if data.len() - pos < 16 {
+            return Err(invalid("truncated pcap record header"));
+        }
+        let sec = endian.u32(&data[pos..]) as u64;
+        let frac = endian.u32(&data[pos+4..]);
+        let len = endian.u32(&data[pos+8..]) as usize;
+        pos += 16;
+        if data.len() - pos < len {
+            return Err(invalid("truncated pcap record"));
+        }
+        let packet = &data[pos..pos+len];
+        pos += len;
+        let time = Duration::new(sec,
+            if nanos { frac } else { frac.saturating_mul(1000) });
+        let (src, dst, seq, payload) = match parse_link(link, packet) {
+            Some(x) => x,
+            None => continue,
+        };
+        let idx = match flows.iter()
+            .position(|f| f.source == src && f.destination == dst)
+        {
+            Some(idx) => idx,
+            None => {
+                flows.push(Flow {
+                    source: src,
+                    destination: dst,
+                    next: seq,
+                    ahead: Vec::new(),
+                });
+                flows.len() - 1
+            }
+        };
+        for (time, payload) in flows[idx].reassemble(seq, time, payload) {
+            result.push(TcpSegment {
+                time,
+                source: src,
+                destination: dst,
+                payload,
+            });
+        }
+    }
+    for mut flow in flows {
+        let next = flow.next;
+        flow.ahead.sort_by_key(|&(seq, _, _)| seq.wrapping_sub(next));
+        while let Some(&(seq, time, _)) = flow.ahead.first() {
+            // Skip the gap which is never filled
+            flow.next = seq;
+            for (time, payload) in flow.take_ready(time) {
+                result.push(TcpSegment {
+                    time,
+                    source: flow.source,
+                    destination: flow.destination,
+                    payload,
+                });
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn parse_link(link: u32, packet: &[u8])
+    -> Option<(SocketAddr, SocketAddr, u32, &[u8])>
+{
+    match link {
+        // Ethernet
+        1 => {
+            if packet.len() < 14 {
+                return None;
+            }
+            let mut ethertype = be16(&packet[12..]);
+            let mut offset = 14;
+            // 802.1Q VLAN tag
+            if ethertype == 0x8100 && packet.len() >= 18 {
+                ethertype = be16(&packet[16..]);
+                offset = 18;
+            }
+            match ethertype {
+                0x0800 | 0x86DD => parse_ip(&packet[offset..]),
+                _ => None,
+            }
+        }
+        // BSD loopback, the address family is in host byte order
+        0 => {
+            if packet.len() < 4 {
+                return None;
+            }
+            parse_ip(&packet[4..])
+        }
+        // Raw IP
+        12 | 101 => parse_ip(packet),
+        // Linux cooked capture
+        113 => {
+            if packet.len() < 16 {
+                return None;
+            }
+            parse_ip(&packet[16..])
+        }
+        _ => None,
+    }
+}
+
+fn parse_ip(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, u32, &[u8])> {
+    if packet.is_empty() {
+        return None;
+    }
+    match packet[0] >> 4 {
+        4 => {
+            if packet.len() < 20 {
+                return None;
+            }
+            let header = ((packet[0] & 0xf) as usize) * 4;
+            let total = be16(&packet[2..]) as usize;
+            // Fragmented packets are not supported
+            let fragment = be16(&packet[6..]);
+            if packet[9] != 6 || fragment & 0x3fff != 0 ||
+                header < 20 || total < header || total > packet.len()
+            {
+                return None;
+            }
+            let src = Ipv4Addr::new(packet[12], packet[13],
+                                    packet[14], packet[15]);
+            let dst = Ipv4Addr::new(packet[16], packet[17],
+                                    packet[18], packet[19]);
+            parse_tcp(&packet[header..total]).map(|(sp, dp, seq, data)| {
+                (SocketAddr::V4(SocketAddrV4::new(src, sp)),
+                 SocketAddr::V4(SocketAddrV4::new(dst, dp)),
+                 seq, data)
+            })
+        }
+        6 => {
+            if packet.len() < 40 {
+                return None;
+            }
+            let total = 40 + be16(&packet[4..]) as usize;
+            // Extension headers are not supported
+            if packet[6] != 6 || total > packet.len() {
+                return None;
+            }
+            let src = ipv6(&packet[8..24]);
+            let dst = ipv6(&packet[24..40]);
+            parse_tcp(&packet[40..total]).map(|(sp, dp, seq, data)| {
+                (SocketAddr::V6(SocketAddrV6::new(src, sp, 0, 0)),
+                 SocketAddr::V6(SocketAddrV6::new(dst, dp, 0, 0)),
+                 seq, data)
+            })
+        }
+        _ => None,
+    }
+}
+
+fn ipv6(data: &[u8]) -> Ipv6Addr {
+    Ipv6Addr::new(be16(&data[0..]), be16(&data[2..]),
+                  be16(&data[4..]), be16(&data[6..]),
+                  be16(&data[8..]), be16(&data[10..]),
+                  be16(&data[12..]), be16(&data[14..]))
+}
+
+fn parse_tcp(segment: &[u8]) -> Option<(u16, u16, u32, &[u8])> {
+    if segment.len() < 20 {
+        return None;
+    }
+    let offset = ((segment[12] >> 4) as usize) * 4;
+    if offset < 20 || offset > segment.len() {
+        return None;
+    }
+    let mut seq = be32(&segment[4..]);
+    // SYN occupies one sequence number
+    if segment[13] & 0x02 != 0 {
+        seq = seq.wrapping_add(1);
+    }
+    Some((be16(&segment[0..]), be16(&segment[2..]), seq, &segment[offset..]))
+}
+
+#[cfg(test)]
+pub mod self_test {
+    use std::time::Duration;
+    use super::tcp_segments;
+
+    /// Timestamp (ms), source, source port, destination, destination port,
+    /// sequence number and payload of a TCP packet
+    pub type Packet<'a> = (u32, [u8; 4], u16, [u8; 4], u16, u32, &'a [u8]);
+
+    fn le(v: u32) -> Vec<u8> {
+        vec![v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+    }
+
+    fn tcp(sport: u16, dport: u16, seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(&[(sport >> 8) as u8, sport as u8,
+                      (dport >> 8) as u8, dport as u8]);
+        data.extend(&[(seq >> 24) as u8, (seq >> 16) as u8,
+                      (seq >> 8) as u8, seq as u8]);
+        data.extend(&[0, 0, 0, 0, 0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+        data.extend(payload);
+        data
+    }
+
+    fn ipv4(&(_, src, sport, dst, dport, seq, payload): &Packet) -> Vec<u8> {
+        let total = 40 + payload.len();
+        let mut data = Vec::new();
+        data.extend(&[0x45, 0, (total >> 8) as u8, total as u8,
+                      0, 0, 0x40, 0, 64, 6, 0, 0]);
+        data.extend(&src);
+        data.extend(&dst);
+        data.extend(tcp(sport, dport, seq, payload));
+        data
+    }
+
+    /// Builds a little-endian pcap file from link-layer frames
+    ///
+    /// Each frame comes with the seconds and the fraction of a second
+    /// (micro- or nanoseconds depending on `magic`).
+    fn pcap(magic: u32, link: u32, frames: &[(u32, u32, Vec<u8>)])
+        -> Vec<u8>
+    {
+        let mut data = Vec::new();
+        data.extend(le(magic));
+        data.extend(&[2, 0, 4, 0]);
+        data.extend(le(0));
+        data.extend(le(0));
+        data.extend(le(65535));
+        data.extend(le(link));
+        for &(sec, frac, ref frame) in frames {
+            data.extend(le(sec));
+            data.extend(le(frac));
+            data.extend(le(frame.len() as u32));
+            data.extend(le(frame.len() as u32));
+            data.extend(frame);
+        }
+        data
+    }
+
+    /// Builds a little-endian pcap file with raw IPv4 packets
+    pub fn capture(packets: &[Packet]) -> Vec<u8> {
+        let frames: Vec<_> = packets.iter()
+            .map(|p| (p.0 / 1000, p.0 % 1000 * 1000, ipv4(p)))
+            .collect();
+        pcap(0xa1b2c3d4, 101, &frames)
+    }
+
+    #[test]
+    fn segments() {
+        let cli = [10, 0, 0, 1];
+        let srv = [10, 0, 0, 2];
+        let data = capture(&[
+            (1000, cli, 5555, srv, 80, 100, b"GET / "),
+            (1010, srv, 80, cli, 5555, 700, b"HTTP/1.1"),
+            // retransmission
+            (1020, cli, 5555, srv, 80, 100, b"GET / "),
+            (1030, cli, 5555, srv, 80, 106, b"HTTP/1.0\r\n"),
+        ]);
+        let segs = tcp_segments(&data).unwrap();
+        assert_eq!(segs.len(), 3);
+        assert_eq!(segs[0].source, "10.0.0.1:5555".parse().unwrap());
+        assert_eq!(segs[0].payload, b"GET / ");
+        assert_eq!(segs[1].destination, "10.0.0.1:5555".parse().unwrap());
+        assert_eq!(segs[2].payload, b"HTTP/1.0\r\n");
+        assert_eq!(segs[2].time, Duration::from_millis(1030));
+    }
+
+    #[test]
+    fn reordered() {
+        let cli = [10, 0, 0, 1];
+        let srv = [10, 0, 0, 2];
+        let data = capture(&[
+            (1000, cli, 5555, srv, 80, 100, b"GET "),
+            (1010, cli, 5555, srv, 80, 107, b"HTTP"),
+            (1020, cli, 5555, srv, 80, 104, b"/x "),
+            // overlaps both the data seen and the new data
+            (1030, cli, 5555, srv, 80, 109, b"TP/1.1"),
+            // the gap at 115 is never filled
+            (1040, cli, 5555, srv, 80, 120, b"tail"),
+            (1050, cli, 5555, srv, 80, 117, b"a"),
+        ]);
+        let segs: Vec<_> = tcp_segments(&data).unwrap().into_iter()
+            .map(|s| (s.time, s.payload)).collect();
+        let ms = Duration::from_millis;
+        assert_eq!(segs, vec![
+            (ms(1000), b"GET ".to_vec()),
+            (ms(1020), b"/x ".to_vec()),
+            (ms(1020), b"HTTP".to_vec()),
+            (ms(1030), b"/1.1".to_vec()),
+            (ms(1050), b"a".to_vec()),
+            (ms(1040), b"tail".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn ethernet() {
+        let packet = (0, [10, 0, 0, 1], 5555, [10, 0, 0, 2], 80, 1, &b"hi"[..]);
+        let mut frame = vec![0; 12];
+        frame.extend(&[0x08, 0x00]);
+        frame.extend(ipv4(&packet));
+        let mut vlan = vec![0; 12];
+        vlan.extend(&[0x81, 0x00, 0x00, 0x05, 0x08, 0x00]);
+        vlan.extend(ipv4(&(0, [10, 0, 0, 1], 5555, [10, 0, 0, 2], 80, 3,
+                           b"!")));
+        let mut arp = vec![0; 12];
+        arp.extend(&[0x08, 0x06, 0, 0]);
+        let data = pcap(0xa1b2c3d4, 1,
+            &[(1, 0, frame), (2, 0, arp), (3, 0, vlan)]);
+        let segs = tcp_segments(&data).unwrap();
+        assert_eq!(segs.len(), 2);
+        assert_eq!(segs[0].source, "10.0.0.1:5555".parse().unwrap());
+        assert_eq!(segs[0].payload, b"hi");
+        assert_eq!(segs[1].payload, b"!");
+        assert_eq!(segs[1].time, Duration::new(3, 0));
+    }
+
+    #[test]
+    fn ipv6() {
+        let payload = tcp(5555, 80, 1, b"hello");
+        let mut frame = vec![0x60, 0, 0, 0,
+            (payload.len() >> 8) as u8, payload.len() as u8, 6, 64];
+        frame.extend(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        frame.extend(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        frame.extend(payload);
+        let data = pcap(0xa1b2c3d4, 101, &[(1, 0, frame)]);
+        let segs = tcp_segments(&data).unwrap();
+        assert_eq!(segs.len(), 1);
+        assert_eq!(segs[0].source, "[fd00::1]:5555".parse().unwrap());
+        assert_eq!(segs[0].destination, "[fd00::2]:80".parse().unwrap());
+        assert_eq!(segs[0].payload, b"hello");
+    }
+
+    #[test]
+    fn nanoseconds() {
+        let packet = ipv4(&(0, [10, 0, 0, 1], 5555, [10, 0, 0, 2], 80, 1,
+                            b"hi"));
+        let data = pcap(0xa1b23c4d, 101, &[(2, 500, packet)]);
+        let segs = tcp_segments(&data).unwrap();
+        assert_eq!(segs.len(), 1);
+        assert_eq!(segs[0].time, Duration::new(2, 500));
+    }
+
+    #[test]
+    fn linux_cooked() {
+        let mut frame = vec![0; 14];
+        frame.extend(&[0x08, 0x00]);
+        frame.extend(ipv4(&(0, [10, 0, 0, 1], 5555, [10, 0, 0, 2], 80, 1,
+                            b"hi")));
+        let data = pcap(0xa1b2c3d4, 113, &[(1, 0, frame)]);
+        let segs = tcp_segments(&data).unwrap();
+        assert_eq!(segs.len(), 1);
+        assert_eq!(segs[0].destination, "10.0.0.2:80".parse().unwrap());
+        assert_eq!(segs[0].payload, b"hi");
+    }
+}
@@ -1,31 +1,164 @@
 use std::io;
+use std::cmp;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::cell::{RefCell, RefMut};
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
 
 use rotor::mio;
-use rotor::{Scope, Time, PollOpt, EventSet};
-use rotor::{_scope, _Timeo, _Notify, _LoopApi};
+use expect::{Expected, OpCursor, check_ordered, check_unordered};
+use context::ComposedCtx;
+use seq::next_seq;
+use rotor::{Machine, Response, Scope, EarlyScope, Time, PollOpt, EventSet};
+use rotor::{_scope, _early_scope, _Notify, _LoopApi};
+use rotor::void::Void;
 
 /// Operation that was done with Scope
-#[derive(Debug, PartialEq, Eq)]
+///
+/// Each operation carries the token of the scope it was issued from.
+/// `Shutdown` also has the time of the mock loop and the reason set with
+/// `ShutdownReason::set()` if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Operation {
-    Register(EventSet, PollOpt),
-    Reregister(EventSet, PollOpt),
-    Deregister,
-    Shutdown,
+    Register(mio::Token, EventSet, PollOpt),
+    Reregister(mio::Token, EventSet, PollOpt),
+    Deregister(mio::Token),
+    ClearTimeout(mio::Token),
+    Shutdown(mio::Token, Time, Option<String>),
+}
+
+impl Operation {
+    /// Token of the scope the operation was issued from
+    pub fn token(&self) -> mio::Token {
+        match *self {
+            Operation::Register(token, _, _) => token,
+            Operation::Reregister(token, _, _) => token,
+            Operation::Deregister(token) => token,
+            Operation::ClearTimeout(token) => token,
+            Operation::Shutdown(token, _, _) => token,
+        }
+    }
+}
+
+/// Kind of event delivered by `MockLoop::run_for()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopEvent {
+    Timeout,
+    Wakeup,
+}
+
+/// A reason of the next loop shutdown (see `MockLoop::shutdown_reason`)
+///
+/// Put it into the context of a test and set the reason in the hook which
+/// is called before `shutdown_loop()`, e.g. in the method of a context
+/// trait which reports fatal errors.
+#[derive(Debug, Clone)]
+pub struct ShutdownReason(Arc<Mutex<Option<String>>>);
+
+impl ShutdownReason {
+    /// Set the reason for the next `Shutdown` operation
+    pub fn set<S: Into<String>>(&self, reason: S) {
+        *self.0.lock().expect("Poisoned ShutdownReason") = Some(reason.into());
+    }
+}
+
+/// A timeout requested by a state machine (see `MockLoop::pending_timeouts`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingTimeout {
+    /// Token of the scope the timeout was requested from
+    pub token: mio::Token,
+    /// Requested delay in milliseconds
+    pub delay_ms: u64,
+    /// Time of the mock loop when the timeout expires
+    pub deadline: Time,
+}
+
+/// Part of the mock loop state which is shared with mock sockets
+///
+/// Mock sockets get it when registered through the `MockLoop`.
+pub struct LoopState {
+    ready: Vec<(mio::Token, EventSet)>,
+    now: Time,
+    // Called when the clock changes, removed when they return false
+    clock_watchers: Vec<ClockWatcher>,
+}
+
+type ClockWatcher = Box<dyn FnMut(Time) -> bool + Send>;
+
+type OperationFilter = Box<dyn Fn(&Operation) -> bool>;
+
+// Tokens and responses of machines created by `MockLoop::handle_spawn()`
+type Children<M> = Vec<(mio::Token, Response<M, Void>)>;
+
+// Tick of the timer of the event loop (about 35 years), so that its
+// timeouts never fire and all of them have the same tick (see
+// `Handler::clear_timeout`)
+const TIMER_TICK_MS: u64 = 1 << 40;
+
+thread_local! {
+    // The loop which is (re)registering a socket at the moment
+    static REGISTERING: RefCell<Option<Arc<Mutex<LoopState>>>> =
+        const { RefCell::new(None) };
 }
 
 struct Handler {
     operations: Vec<Operation>,
+    // Sequence number (see `next_seq()`) and time of each operation
+    stamps: Vec<(u64, Time)>,
+    // Pending timeouts with their ids, the id is also the value stored in
+    // the timer of the event loop
+    timeouts: Vec<(u64, mio::Timeout, PendingTimeout)>,
+    next_timeout_id: u64,
+    // Token of the last created scope
+    token: mio::Token,
+    state: Arc<Mutex<LoopState>>,
+    // Tokens of captured `Notifier::wakeup()` calls
+    wakeups: Vec<mio::Token>,
+    // Only polled to capture wakeups. (De)registrations are passed to it,
+    // so that `MemIo` and other mock sockets can keep track of them. It's
+    // `None` only while it's being polled.
+    event_loop: Option<mio::EventLoop<Handler>>,
+    // Tokens above any token used so far
+    next_token: usize,
+    // Tokens of the machines created by `handle_spawn()`
+    spawned: Vec<mio::Token>,
+    // Set by `shutdown_loop()`, isn't reset by `take_operations()`
+    shutdown: bool,
+    shutdown_reason: ShutdownReason,
+    // Maximum number of pending timeouts
+    timer_capacity: usize,
+    // Created on the first overflow of `timer_capacity`, see `full_timer()`
+    full_timer: Option<mio::EventLoop<Handler>>,
+    // Deadlines are rounded up to the multiple of it (in milliseconds)
+    timer_tick: u64,
+    // Errors for the next (re|de)registrations matching the predicate
+    failures: Vec<(OperationFilter, io::Error)>,
 }
 
 /// A mock loop implementation
 ///
 /// It's not actually fully working loop, just a thing which you can get
 /// a `Scope` object from.
+///
+/// Registrations done through the scope are recorded as operations and also
+/// passed to the socket itself, so `MemIo::registration()` reflects them.
+/// When input arrives to a registered `MemIo` its token is marked as ready
+/// (see `ready_tokens()`), so the test knows which machines to call
+/// `ready()` on.
 pub struct MockLoop<C> {
-    event_loop: mio::EventLoop<Handler>,
     handler: Handler,
     context: C,
     channel: mio::Sender<_Notify>,
+    expected: Vec<Expected>,
+    // Wakeups added by `schedule_wakeup()` which are not due yet
+    scheduled: Vec<(Time, mio::Token)>,
+    machines: HashMap<mio::Token, Box<dyn Any>>,
+    check_cleanup: bool,
+    // Delay of delivering timeouts after their deadlines
+    timer_jitter: Duration,
 }
 
 impl<C> MockLoop<C> {
@@ -33,80 +166,1108 @@ impl<C> MockLoop<C> {
     ///
     /// The `ctx` is a context, and it's type must be compatible
     /// to your state machine.
+    ///
+    /// `Scope` can't be created without a `mio::Sender` and timeouts
+    /// without a timer, so the mock loop needs a real `mio::EventLoop`
+    /// (which is never run). It holds file descriptors until the mock loop
+    /// is dropped.
     pub fn new(ctx: C) -> MockLoop<C> {
-        let eloop = mio::EventLoop::new()
-                .expect("event loop is crated");
-        MockLoop {
+        let eloop = mio::EventLoop::configured(loop_config())
+            .expect("event loop is crated");
+        MockLoop::with_loop(ctx, eloop)
+    }
+    /// Create a mock loop with a small queue for wakeups
+    ///
+    /// When the queue is full `Notifier::wakeup()` returns
+    /// `WakeupError::Full`, until wakeups are collected by
+    /// `pending_wakeups()` or delivered. Capacity is rounded up to a power
+    /// of two, and is at least two.
+    pub fn with_notify_capacity(ctx: C, capacity: usize) -> MockLoop<C> {
+        let mut config = loop_config();
+        config.notify_capacity(capacity);
+        let eloop = mio::EventLoop::configured(config)
+                .expect("event loop is created");
+        MockLoop::with_loop(ctx, eloop)
+    }
+    fn with_loop(ctx: C, eloop: mio::EventLoop<Handler>) -> MockLoop<C> {
+        let mut result = MockLoop {
+            channel: eloop.channel(),
             handler: Handler {
                 operations: Vec::new(),
+                stamps: Vec::new(),
+                timeouts: Vec::new(),
+                next_timeout_id: 0,
+                token: mio::Token(0),
+                state: Arc::new(Mutex::new(LoopState {
+                    ready: Vec::new(),
+                    now: Time::zero(),
+                    clock_watchers: Vec::new(),
+                })),
+                wakeups: Vec::new(),
+                event_loop: Some(eloop),
+                next_token: 0,
+                spawned: Vec::new(),
+                shutdown: false,
+                shutdown_reason: ShutdownReason(Arc::new(Mutex::new(None))),
+                timer_capacity: usize::MAX,
+                full_timer: None,
+                timer_tick: 0,
+                failures: Vec::new(),
             },
-            channel: eloop.channel(),
-            event_loop: eloop,
             context: ctx,
-        }
+            expected: Vec::new(),
+            scheduled: Vec::new(),
+            machines: HashMap::new(),
+            check_cleanup: false,
+            timer_jitter: Duration::from_millis(0),
+        };
+        // The first poll moves the timer to its first tick, after that
+        // all timeouts get the same tick
+        result.collect_wakeups();
+        result
     }
     /// Get a scope object for specified token
     ///
     /// This is useful to call state machine actions directly
-    pub fn scope(&mut self, x: usize) -> Scope<C> {
-        _scope(Time::zero(), mio::Token(x),
-            &mut self.context,
+    pub fn scope<'a>(&'a mut self, x: usize) -> Scope<'a, C> {
+        self.project_scope(x, |ctx| ctx)
+    }
+    /// Get a scope with a part of the context, for specified token
+    ///
+    /// This is useful to test a child of a composed state machine, when its
+    /// context is accessed through `AsMut` of the composed context.
+    pub fn scope_as<'a, D>(&'a mut self, x: usize) -> Scope<'a, D>
+        where C: AsMut<D>
+    {
+        self.project_scope(x, |ctx| ctx.as_mut())
+    }
+    /// Call the function with a scope which has `&mut T` context
+    ///
+    /// This is useful for code which accesses the context as a trait
+    /// object, e.g. `Scope<&mut dyn Trait>`, see `DynContext`.
+    pub fn with_dyn_scope<T: ?Sized, F, R>(&mut self, x: usize, f: F) -> R
+        where C: AsMut<T>,
+              F: FnOnce(&mut Scope<&mut T>) -> R,
+    {
+        let now = self.handler.state.lock().expect("Poisoned MockLoop").now;
+        self.handler.token = mio::Token(x);
+        if x >= self.handler.next_token {
+            self.handler.next_token = x + 1;
+        }
+        let mut ctx = self.context.as_mut();
+        f(&mut _scope(now, mio::Token(x),
+            &mut ctx,
+            &mut self.channel,
+            &mut self.handler))
+    }
+    // Creates a scope with the part of the context returned by the function
+    fn project_scope<'a, D, F>(&'a mut self, x: usize, f: F) -> Scope<'a, D>
+        where F: FnOnce(&'a mut C) -> &'a mut D
+    {
+        let now = self.handler.state.lock().expect("Poisoned MockLoop").now;
+        self.handler.token = mio::Token(x);
+        if x >= self.handler.next_token {
+            self.handler.next_token = x + 1;
+        }
+        _scope(now, mio::Token(x),
+            f(&mut self.context),
             &mut self.channel,
             &mut self.handler)
     }
+    /// Get an early scope object for specified token
+    ///
+    /// This is what constructors passed to `Loop::add_machine_with` get,
+    /// i.e. the machine is created before the context is initialized.
+    /// Operations and wakeups are recorded the same way as for `scope()`.
+    pub fn early_scope<'a>(&'a mut self, x: usize) -> EarlyScope<'a> {
+        self.handler.token = mio::Token(x);
+        if x >= self.handler.next_token {
+            self.handler.next_token = x + 1;
+        }
+        _early_scope(mio::Token(x), &mut self.channel, &mut self.handler)
+    }
+    /// Call the function with scopes for two tokens at once
+    ///
+    /// This allows machine `x` to directly trigger machine `y` (e.g. wake it
+    /// up) in a single test step. Operations are recorded with the respective
+    /// token of each scope. The first scope gets the context of the loop,
+    /// and the second one a clone of it. Changes made to the context
+    /// through the second scope are lost when the function returns, unless
+    /// the context shares its state (e.g. `Rc<RefCell<_>>`).
+    pub fn with_scopes<F, R>(&mut self, x: usize, y: usize, f: F) -> R
+        where F: FnOnce(&mut Scope<C>, &mut Scope<C>) -> R,
+              C: Clone,
+    {
+        let now = self.now();
+        let next = cmp::max(x, y) + 1;
+        if next > self.handler.next_token {
+            self.handler.next_token = next;
+        }
+        let mut context = self.context.clone();
+        let mut channel = self.channel.clone();
+        let handler = RefCell::new(&mut self.handler);
+        let mut api_x = TokenApi { token: mio::Token(x), handler: &handler };
+        let mut api_y = TokenApi { token: mio::Token(y), handler: &handler };
+        f(&mut _scope(now, mio::Token(x),
+                &mut self.context, &mut self.channel, &mut api_x),
+          &mut _scope(now, mio::Token(y),
+                &mut context, &mut channel, &mut api_y))
+    }
 
     pub fn ctx(&mut self) -> &mut C {
         &mut self.context
     }
+    /// Replace the context, everything else is kept
+    ///
+    /// Useful for table-driven tests which need a fresh context per case.
+    pub fn set_ctx(&mut self, ctx: C) {
+        self.context = ctx;
+    }
+    /// Same as `set_ctx()` but returns the old context
+    pub fn replace_ctx(&mut self, ctx: C) -> C {
+        mem::replace(&mut self.context, ctx)
+    }
+    /// Current time of the mock loop
+    ///
+    /// This is what `scope.now()` returns. The clock starts at `Time::zero()`
+    /// and only changes with `advance()` and `set_time()`.
+    pub fn now(&self) -> Time {
+        self.handler.state.lock().expect("Poisoned MockLoop").now
+    }
+    /// Move the clock of the mock loop forward
+    pub fn advance(&mut self, duration: Duration) {
+        let now = self.now() + duration;
+        self.set_time(now);
+    }
+    /// Set the clock of the mock loop, time can't go backwards
+    ///
+    /// Mock sockets registered through the loop follow its clock, e.g.
+    /// input pushed by `MemIo::push_bytes_after()` becomes readable.
+    pub fn set_time(&mut self, now: Time) {
+        let mut watchers = {
+            let mut state = self.handler.state.lock()
+                .expect("Poisoned MockLoop");
+            assert!(now >= state.now, "time can't go backwards");
+            state.now = now;
+            mem::take(&mut state.clock_watchers)
+        };
+        // Called without the lock, as sockets use the state too
+        watchers.retain_mut(|watcher| watcher(now));
+        {
+            let mut state = self.handler.state.lock()
+                .expect("Poisoned MockLoop");
+            watchers.append(&mut state.clock_watchers);
+            state.clock_watchers = watchers;
+        }
+        self.release_scheduled();
+    }
+    /// Send a wakeup to the token when the clock reaches the time
+    ///
+    /// This models another thread calling `Notifier::wakeup()` at that
+    /// point of time. When the time comes, the wakeup is added to
+    /// `pending_wakeups()`. Wakeups scheduled for the current time or
+    /// earlier are added immediately.
+    pub fn schedule_wakeup(&mut self, token: mio::Token, at: Time) {
+        self.scheduled.push((at, token));
+        self.release_scheduled();
+    }
+    // Moves scheduled wakeups which are due to the list of pending ones
+    fn release_scheduled(&mut self) {
+        let now = self.now();
+        if self.scheduled.iter().all(|&(at, _)| at > now) {
+            return;
+        }
+        // Wakeups sent to the channel earlier go first
+        self.collect_wakeups();
+        self.scheduled.sort_by_key(|&(at, _)| at);
+        let due = self.scheduled.iter().take_while(|&&(at, _)| at <= now)
+            .count();
+        let wakeups = self.scheduled.drain(..due).map(|(_, token)| token);
+        self.handler.wakeups.extend(wakeups);
+    }
+    /// Deliver wakeups and timeouts in order, moving the clock up to
+    /// `duration` forward
+    ///
+    /// The function is called for each event with the token, the kind of
+    /// the event and the scope, and is expected to call the respective
+    /// action of the state machine. The clock is moved to the time of each
+    /// event. Like in the real loop, wakeups are delivered before timeouts
+    /// due at the same time. Events caused by the function itself are
+    /// delivered too, if they are due before the end of the period.
+    pub fn run_for<F>(&mut self, duration: Duration, mut f: F)
+        where F: FnMut(mio::Token, LoopEvent, &mut Scope<C>)
+    {
+        let end = self.now() + duration;
+        loop {
+            self.collect_wakeups();
+            if !self.handler.wakeups.is_empty() {
+                let token = self.handler.wakeups.remove(0);
+                f(token, LoopEvent::Wakeup, &mut self.scope(token.0));
+                continue;
+            }
+            let now = self.now();
+            let jitter = self.timer_jitter;
+            let timeouts = self.pending_timeouts();
+            let due = timeouts.first().filter(|t| t.deadline + jitter <= now);
+            if let Some(timeout) = due {
+                self.take_timeout(|t| t == timeout);
+                f(timeout.token, LoopEvent::Timeout,
+                  &mut self.scope(timeout.token.0));
+                continue;
+            }
+            let next = timeouts.first().map(|t| t.deadline + jitter)
+                .into_iter()
+                .chain(self.scheduled.iter().map(|&(at, _)| at))
+                .min();
+            match next {
+                Some(next) if next <= end => self.set_time(next),
+                _ => {
+                    self.set_time(end);
+                    break;
+                }
+            }
+        }
+    }
+    /// Operations done through the scopes so far
+    pub fn operations(&self) -> &[Operation] {
+        &self.handler.operations
+    }
+    /// A cursor to check operations done so far one by one
+    pub fn cursor<'a>(&'a self) -> OpCursor<'a> {
+        OpCursor::new(&self.handler.operations)
+    }
+    /// Operations done through the scopes of the token so far
+    pub fn ops_for(&self, token: mio::Token) -> Vec<Operation> {
+        self.handler.operations.iter()
+            .filter(|op| op.token() == token)
+            .cloned().collect()
+    }
+    /// Sequence of interests of the token's registrations
+    ///
+    /// Each `register` and `reregister` adds the interest, and `deregister`
+    /// adds `EventSet::none()`. Other operations are skipped.
+    pub fn interests(&self, token: mio::Token) -> Vec<EventSet> {
+        self.handler.operations.iter().filter_map(|op| match *op {
+            Operation::Register(t, events, _) if t == token => Some(events),
+            Operation::Reregister(t, events, _) if t == token => Some(events),
+            Operation::Deregister(t) if t == token => Some(EventSet::none()),
+            _ => None,
+        }).collect()
+    }
+    /// Check that interests of the token changed exactly like this
+    ///
+    /// Panics with both sequences if `interests(token)` doesn't match.
+    pub fn assert_interests(&self, token: mio::Token, expected: &[EventSet]) {
+        let actual = self.interests(token);
+        if actual != expected {
+            panic!("interests of {:?} don't match:\n  expected {:?}\n  \
+                got      {:?}", token, expected, actual);
+        }
+    }
+    /// Forget operations done so far
+    ///
+    /// Useful to check only operations done after the setup phase of a test.
+    pub fn clear_operations(&mut self) {
+        self.handler.operations.clear();
+        self.handler.stamps.clear();
+    }
+    /// Same as `operations()` but also clears the list
+    pub fn take_operations(&mut self) -> Vec<Operation> {
+        self.handler.stamps.clear();
+        self.handler.operations.drain(..).collect()
+    }
+    /// Operations done so far, each stamped with a sequence number
+    ///
+    /// The sequence is shared with I/O events of `MemIo` (see
+    /// `MemIo::sequenced_timeline()`), so comparing the numbers shows
+    /// whether a write happened before or after a reregistration.
+    pub fn sequenced_operations(&self) -> Vec<(u64, Operation)> {
+        self.handler.stamps.iter().map(|&(seq, _)| seq)
+            .zip(self.handler.operations.iter().cloned())
+            .collect()
+    }
+    /// Operations done so far, each stamped with the time of the loop
+    pub fn timed_operations(&self) -> Vec<(Time, Operation)> {
+        self.handler.stamps.iter().map(|&(_, time)| time)
+            .zip(self.handler.operations.iter().cloned())
+            .collect()
+    }
+    /// Add operations expected to be done next, in this order
+    ///
+    /// Expectations are checked by `verify()`.
+    pub fn expect(&mut self, ops: &[Expected]) {
+        self.expected.extend(ops.iter().cloned());
+    }
+    /// Check that operations done so far match the expectations
+    ///
+    /// Both operations and expectations are cleared. Panics with the list
+    /// of expected and actual operations if they don't match, followed by
+    /// the time of each operation.
+    pub fn verify(&mut self) {
+        let expected = mem::take(&mut self.expected);
+        let timed = self.timed_operations();
+        let actual = self.take_operations();
+        if let Err(text) = check_ordered(&expected, &actual) {
+            panic!("{}{}", text, format_timeline(&timed));
+        }
+    }
+    /// Same as `verify()` but operations may be done in any order
+    ///
+    /// This is useful for composed machines which interleave operations of
+    /// the children. If `allow_extra` is true, operations which are not
+    /// expected are ignored.
+    pub fn verify_unordered(&mut self, allow_extra: bool) {
+        let expected = mem::take(&mut self.expected);
+        let timed = self.timed_operations();
+        let actual = self.take_operations();
+        if let Err(text) = check_unordered(&expected, &actual, allow_extra) {
+            panic!("{}{}", text, format_timeline(&timed));
+        }
+    }
+    /// Make the next operation matching the predicate fail with the error
+    ///
+    /// Only `register`, `reregister` and `deregister` can fail. The failed
+    /// operation is still recorded, but isn't passed to the socket. Each
+    /// error is returned once, errors injected earlier are checked first.
+    pub fn fail_operation<P>(&mut self, predicate: P, error: io::Error)
+        where P: Fn(&Operation) -> bool + 'static
+    {
+        self.handler.failures.push((Box::new(predicate), error));
+    }
+    /// A handle to set the reason of the next loop shutdown
+    pub fn shutdown_reason(&self) -> ShutdownReason {
+        self.handler.shutdown_reason.clone()
+    }
+    /// Returns true if any state machine called `shutdown_loop()`
+    ///
+    /// Unlike the `Shutdown` operation, the flag stays set after
+    /// `take_operations()`.
+    pub fn is_shutdown_requested(&self) -> bool {
+        self.handler.shutdown
+    }
+    /// Timeouts requested by state machines and not cleared yet
+    ///
+    /// Timeouts are sorted by deadline.
+    pub fn pending_timeouts(&self) -> Vec<PendingTimeout> {
+        let mut result: Vec<_> = self.handler.timeouts.iter()
+            .map(|(_, _, t)| *t).collect();
+        result.sort_by_key(|t| t.deadline);
+        result
+    }
+    /// Pending timeouts of the token, sorted by deadline
+    ///
+    /// A machine (especially a composed one) may have any number of
+    /// timeouts at once. They are delivered in this order by
+    /// `fire_timeout()` and `fire_due_timeouts()`, timeouts with the same
+    /// deadline in the order they were requested.
+    pub fn timeouts_for(&self, token: mio::Token) -> Vec<PendingTimeout> {
+        self.pending_timeouts().into_iter()
+            .filter(|t| t.token == token).collect()
+    }
+    /// Limit the number of pending timeouts
+    ///
+    /// When the limit is reached `timeout_ms()` fails with `TimerError`,
+    /// like the timer of the real loop when its capacity is exhausted.
+    pub fn set_timer_capacity(&mut self, capacity: usize) {
+        self.handler.timer_capacity = capacity;
+    }
+    /// Round deadlines of timeouts like a timer with the resolution
+    ///
+    /// The timer of the real loop fires timeouts on ticks (100ms by
+    /// default), so deadlines are rounded up to the next tick, counting from
+    /// `Time::zero()`, and are at least one tick in the future. Zero
+    /// duration (the default) means no rounding.
+    pub fn set_timer_resolution(&mut self, tick: Duration) {
+        self.handler.timer_tick = tick.as_secs() * 1000
+            + tick.subsec_millis() as u64;
+    }
+    /// Deliver timeouts later than their deadlines by `jitter`
+    ///
+    /// The real timer fires timeouts somewhat after the deadline, so
+    /// `scope.now()` in `Machine::timeout` is rarely equal to the deadline
+    /// stored by the machine. With the jitter `fire_timeout()` and
+    /// `run_for()` move the clock past the deadline, and
+    /// `fire_due_timeouts()` delivers only timeouts which are overdue by
+    /// the jitter. Deadlines in `pending_timeouts()` are not changed.
+    pub fn set_timer_jitter(&mut self, jitter: Duration) {
+        self.timer_jitter = jitter;
+    }
+    /// Deliver the earliest pending timeout of token `x` to the machine
+    ///
+    /// The timeout is removed from the pending list, and the clock is moved
+    /// to its deadline (plus the jitter, see `set_timer_jitter()`) if it's
+    /// not reached yet. Then `Machine::timeout` is
+    /// called with the scope for the token. Panics if the token has no
+    /// pending timeouts.
+    pub fn fire_timeout<M>(&mut self, x: usize, machine: M)
+        -> Response<M, M::Seed>
+        where M: Machine<Context=C>
+    {
+        let timeout = self.take_timeout(|t| t.token == mio::Token(x))
+            .unwrap_or_else(|| panic!("No timeouts pending for token {}", x));
+        let at = timeout.deadline + self.timer_jitter;
+        if at > self.now() {
+            self.set_time(at);
+        }
+        machine.timeout(&mut self.scope(x))
+    }
+    /// Deliver all timeouts with deadline not later than current time
+    ///
+    /// The function is called for each expired timeout in the order of
+    /// deadlines, with the token and the scope, and is expected to call
+    /// `Machine::timeout` of the respective state machine. Timeouts
+    /// requested by the function itself are delivered too, if expired.
+    pub fn fire_due_timeouts<F>(&mut self, mut f: F)
+        where F: FnMut(mio::Token, &mut Scope<C>)
+    {
+        let now = self.now();
+        let jitter = self.timer_jitter;
+        while let Some(timeout) =
+            self.take_timeout(|t| t.deadline + jitter <= now)
+        {
+            f(timeout.token, &mut self.scope(timeout.token.0));
+        }
+    }
+    // Removes the earliest timeout matching the predicate
+    //
+    // Of the timeouts with the same deadline the one requested first is
+    // taken.
+    fn take_timeout<P>(&mut self, predicate: P) -> Option<PendingTimeout>
+        where P: Fn(&PendingTimeout) -> bool
+    {
+        let idx = self.handler.timeouts.iter().enumerate()
+            .filter(|(_, (_, _, t))| predicate(t))
+            .min_by_key(|(_, (_, _, t))| t.deadline)
+            .map(|(idx, _)| idx);
+        idx.map(|idx| {
+            let (_, timeout, t) = self.handler.timeouts.remove(idx);
+            self.handler.event_loop().clear_timeout(timeout);
+            t
+        })
+    }
+    /// Tokens for which wakeups were requested with `Notifier::wakeup()`
+    ///
+    /// Notifiers created by the scopes of the mock loop send wakeups to the
+    /// loop itself, so they are captured here in order of arrival. The same
+    /// token is listed as many times as `wakeup()` was called.
+    pub fn pending_wakeups(&mut self) -> Vec<mio::Token> {
+        self.collect_wakeups();
+        self.handler.wakeups.clone()
+    }
+    /// Deliver a pending wakeup of token `x` to the machine
+    ///
+    /// The first captured wakeup for the token is removed and
+    /// `Machine::wakeup` is called with the scope for the token. Panics if
+    /// there are no wakeups for the token.
+    pub fn deliver_wakeup<M>(&mut self, x: usize, machine: M)
+        -> Response<M, M::Seed>
+        where M: Machine<Context=C>
+    {
+        self.collect_wakeups();
+        let idx = self.handler.wakeups.iter()
+            .position(|&t| t == mio::Token(x))
+            .unwrap_or_else(|| panic!("No wakeups pending for token {}", x));
+        self.handler.wakeups.remove(idx);
+        machine.wakeup(&mut self.scope(x))
+    }
+    /// Drain all the captured wakeups
+    ///
+    /// The function is called for each wakeup in order of arrival, with the
+    /// token and the scope, and is expected to call `Machine::wakeup` of
+    /// the respective state machine. Wakeups sent while processing are
+    /// delivered too.
+    pub fn process_wakeups<F>(&mut self, mut f: F)
+        where F: FnMut(mio::Token, &mut Scope<C>)
+    {
+        loop {
+            self.collect_wakeups();
+            if self.handler.wakeups.is_empty() {
+                break;
+            }
+            let token = self.handler.wakeups.remove(0);
+            f(token, &mut self.scope(token.0));
+        }
+    }
+    /// Create child machines if the response of token `x` is a spawn
+    ///
+    /// This is what the real loop does: the seed is passed to
+    /// `Machine::create` with the scope of a newly allocated token, then
+    /// `Machine::spawned` of the parent is called, which may spawn again.
+    /// Returns the final response of the parent and the tokens and
+    /// responses of the children in order of creation.
+    pub fn handle_spawn<M>(&mut self, x: usize, response: Response<M, M::Seed>)
+        -> (Response<M, Void>, Children<M>)
+        where M: Machine<Context=C>
+    {
+        let mut response = response;
+        let mut children = Vec::new();
+        loop {
+            let mut seed = None;
+            let rest = response.map(|m| m, |s| seed = Some(s));
+            let seed = match seed {
+                Some(seed) => seed,
+                None => {
+                    return (rest.map(|m| m, |()| unreachable!()), children);
+                }
+            };
+            let mut machine = None;
+            rest.wrap(|m| machine = Some(m));
+            let machine = machine.expect("spawn response has a machine");
+            let token = self.allocate_token();
+            self.handler.spawned.push(token);
+            children.push((token, M::create(seed, &mut self.scope(token.0))));
+            response = machine.spawned(&mut self.scope(x));
+        }
+    }
+    /// Allocate a token which is not used yet
+    ///
+    /// Like in the real loop, tokens are allocated sequentially starting
+    /// from zero. Tokens passed to `scope()` are skipped, so allocated tokens
+    /// never collide with hard-coded ones.
+    pub fn allocate_token(&mut self) -> mio::Token {
+        let token = mio::Token(self.handler.next_token);
+        self.handler.next_token += 1;
+        token
+    }
+    /// Tokens of the machines created by `handle_spawn()`
+    pub fn spawned(&self) -> &[mio::Token] {
+        &self.handler.spawned
+    }
+    /// Create a machine with a newly allocated token and keep it in the loop
+    ///
+    /// Machines kept in the loop are driven by `dispatch_ready()`,
+    /// `dispatch_timeout()` and `dispatch_wakeup()`. Returns the token, or
+    /// `None` if the machine stopped right away.
+    ///
+    /// Note: rotor gives no access to the deadline of a `Response`, so
+    /// deadlines returned by machines are silently dropped, here and in
+    /// `dispatch_*()`. Timeouts set with `scope.timeout_ms()` work as
+    /// usual.
+    pub fn add_machine<M, F>(&mut self, f: F) -> Option<mio::Token>
+        where M: Machine<Context=C> + 'static,
+              F: FnOnce(&mut Scope<C>) -> Response<M, Void>,
+    {
+        let token = self.allocate_token();
+        let response = f(&mut self.scope(token.0));
+        self.keep_machine(token, response);
+        self.machines.get(&token).map(|_| token)
+    }
+    /// Put the machine to the loop with specified token
+    pub fn insert_machine<M>(&mut self, token: mio::Token, machine: M)
+        where M: Machine<Context=C> + 'static
+    {
+        if token.0 >= self.handler.next_token {
+            self.handler.next_token = token.0 + 1;
+        }
+        self.machines.insert(token, Box::new(machine));
+    }
+    /// Returns the machine kept in the loop, if there is one of this type
+    pub fn machine<M: 'static>(&self, token: mio::Token) -> Option<&M> {
+        self.machines.get(&token).and_then(|m| m.downcast_ref())
+    }
+    /// Returns true if there is a machine with this token in the loop
+    pub fn has_machine(&self, token: mio::Token) -> bool {
+        self.machines.contains_key(&token)
+    }
+    /// Call `Machine::ready` of the machine kept in the loop
+    ///
+    /// The response is processed like in the real loop: spawned machines
+    /// are created and kept too, and the machine is removed if it has
+    /// stopped. But the deadline of the response is dropped, see
+    /// `add_machine()`. Panics if there is no machine of type `M` with the
+    /// token.
+    pub fn dispatch_ready<M>(&mut self, token: mio::Token, events: EventSet)
+        where M: Machine<Context=C> + 'static
+    {
+        self.dispatch(token, |m: M, scope| m.ready(events, scope))
+    }
+    /// Call `Machine::ready` with events the machine didn't ask for
+    ///
+    /// Real epoll may wake up a machine spuriously, so machines should
+    /// tolerate it. Events are readable and writable ones not included in
+    /// the last interest of the token (see `interests()`), or
+    /// `EventSet::none()` if it includes both. Returns the events passed.
+    /// Otherwise works like `dispatch_ready()`.
+    pub fn dispatch_spurious<M>(&mut self, token: mio::Token) -> EventSet
+        where M: Machine<Context=C> + 'static
+    {
+        let interest = self.interests(token).pop()
+            .unwrap_or(EventSet::none());
+        let mut events = EventSet::readable() | EventSet::writable();
+        events.remove(interest);
+        self.dispatch_ready::<M>(token, events);
+        events
+    }
+    /// Call `Machine::timeout` of the machine kept in the loop
+    ///
+    /// See `dispatch_ready()` for details.
+    pub fn dispatch_timeout<M>(&mut self, token: mio::Token)
+        where M: Machine<Context=C> + 'static
+    {
+        self.dispatch(token, |m: M, scope| m.timeout(scope))
+    }
+    /// Call `Machine::wakeup` of the machine kept in the loop
+    ///
+    /// See `dispatch_ready()` for details.
+    pub fn dispatch_wakeup<M>(&mut self, token: mio::Token)
+        where M: Machine<Context=C> + 'static
+    {
+        self.dispatch(token, |m: M, scope| m.wakeup(scope))
+    }
+    fn dispatch<M, F>(&mut self, token: mio::Token, f: F)
+        where M: Machine<Context=C> + 'static,
+              F: FnOnce(M, &mut Scope<C>) -> Response<M, M::Seed>,
+    {
+        let machine = self.machines.remove(&token)
+            .unwrap_or_else(|| panic!("No machine for {:?}", token))
+            .downcast::<M>()
+            .unwrap_or_else(|_| panic!("Machine {:?} has other type", token));
+        let response = f(*machine, &mut self.scope(token.0));
+        let (response, children) = self.handle_spawn(token.0, response);
+        self.keep_machine(token, response);
+        for (child, response) in children {
+            self.keep_machine(child, response);
+        }
+    }
+    // Keeps the machine of the response, if any. The deadline of the
+    // response is dropped, as rotor has no accessor for it
+    fn keep_machine<M>(&mut self, token: mio::Token,
+        response: Response<M, Void>)
+        where M: Machine<Context=C> + 'static
+    {
+        let mut machine = None;
+        response.wrap(|m| machine = Some(m));
+        match machine {
+            Some(machine) => {
+                self.machines.insert(token, Box::new(machine));
+            }
+            None if self.check_cleanup => self.assert_cleaned_up(token),
+            None => {}
+        }
+    }
+    /// Check cleanup when a machine kept in the loop stops
+    ///
+    /// When enabled, `dispatch_*()` and `add_machine()` panic if a machine
+    /// stops without deregistering its sockets or with pending timeouts.
+    /// See `assert_cleaned_up()`.
+    pub fn set_check_cleanup(&mut self, check: bool) {
+        self.check_cleanup = check;
+    }
+    /// Check that the token has no sockets registered and no timeouts
+    ///
+    /// Sockets are considered registered if there is a `Register` operation
+    /// for the token, not followed by a `Deregister`, so operations cleared
+    /// by a test are not taken into account.
+    pub fn assert_cleaned_up(&self, token: mio::Token) {
+        let ops = self.ops_for(token);
+        let registered = ops.iter()
+            .rposition(|op| matches!(*op, Operation::Register(..)));
+        if let Some(idx) = registered {
+            let deregistered = ops[idx..].iter()
+                .any(|op| matches!(*op, Operation::Deregister(..)));
+            if !deregistered {
+                panic!("{:?} stopped without deregistering its socket: {:?}",
+                    token, ops);
+            }
+        }
+        let timeouts: Vec<_> = self.pending_timeouts().into_iter()
+            .filter(|t| t.token == token).collect();
+        if !timeouts.is_empty() {
+            panic!("{:?} stopped with pending timeouts: {:?}",
+                token, timeouts);
+        }
+    }
+    // Reads wakeups sent to the channel into the handler
+    fn collect_wakeups(&mut self) {
+        let mut event_loop = self.handler.event_loop.take()
+            .expect("mock event loop is not polled");
+        loop {
+            let count = self.handler.wakeups.len();
+            event_loop.run_once(&mut self.handler, Some(0))
+                .expect("mock event loop can be polled");
+            if self.handler.wakeups.len() == count {
+                break;
+            }
+        }
+        self.handler.event_loop = Some(event_loop);
+    }
+    /// Tokens of mock sockets which became ready, with their events
+    ///
+    /// Events are accumulated until `take_ready_tokens()` is called.
+    pub fn ready_tokens(&self) -> Vec<(mio::Token, EventSet)> {
+        self.handler.state.lock().expect("Poisoned MockLoop").ready.clone()
+    }
+    /// Same as `ready_tokens()` but also clears the list
+    pub fn take_ready_tokens(&mut self) -> Vec<(mio::Token, EventSet)> {
+        self.handler.state.lock().expect("Poisoned MockLoop")
+            .ready.drain(..).collect()
+    }
+}
+
+impl<A, B> MockLoop<ComposedCtx<A, B>> {
+    /// Get a scope with the context of the first child
+    pub fn scope_first<'a>(&'a mut self, x: usize) -> Scope<'a, A> {
+        self.project_scope(x, |ctx| &mut ctx.0)
+    }
+    /// Get a scope with the context of the second child
+    pub fn scope_second<'a>(&'a mut self, x: usize) -> Scope<'a, B> {
+        self.project_scope(x, |ctx| &mut ctx.1)
+    }
+}
+
+impl<C: fmt::Debug> fmt::Debug for MockLoop<C> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.handler.state.lock().expect("Poisoned MockLoop");
+        // Wakeups which are still in the channel aren't shown, as
+        // collecting them requires `&mut self`
+        fmt.debug_struct("MockLoop")
+        .field("now", &state.now)
+        .field("operations", &self.handler.operations)
+        .field("timeouts", &self.pending_timeouts())
+        .field("wakeups", &self.handler.wakeups)
+        .field("ready", &state.ready)
+        .field("shutdown", &self.handler.shutdown)
+        .field("context", &self.context)
+        .finish()
+    }
+}
+
+impl LoopState {
+    pub fn now(&self) -> Time {
+        self.now
+    }
+    pub fn watch_clock(&mut self, watcher: ClockWatcher) {
+        self.clock_watchers.push(watcher);
+    }
+    pub fn add_ready(&mut self, token: mio::Token, events: EventSet) {
+        for &mut (tok, ref mut ev) in self.ready.iter_mut() {
+            if tok == token {
+                *ev = *ev | events;
+                return;
+            }
+        }
+        self.ready.push((token, events));
+    }
+}
+
+/// Returns the state of a `MockLoop` if called from `Evented::register`
+/// (or `reregister`) invoked by that loop
+pub fn registering_loop() -> Option<Arc<Mutex<LoopState>>> {
+    REGISTERING.with(|r| r.borrow().clone())
+}
+
+/// Registration of a mock socket in the loop
+///
+/// Recorded by `mio::Evented` implementations of the mock sockets, errors
+/// mimic the ones returned by epoll. When registered through the
+/// `MockLoop` it also keeps the state of that loop.
+#[derive(Default)]
+pub struct Registration {
+    registration: Option<(mio::Token, EventSet, PollOpt)>,
+    mock_loop: Option<Arc<Mutex<LoopState>>>,
+}
+
+impl Registration {
+    pub fn register(&mut self, token: mio::Token, interest: EventSet,
+        opts: PollOpt) -> io::Result<()>
+    {
+        if self.registration.is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                "mock socket is already registered"));
+        }
+        self.registration = Some((token, interest, opts));
+        self.mock_loop = registering_loop();
+        Ok(())
+    }
+    pub fn reregister(&mut self, token: mio::Token, interest: EventSet,
+        opts: PollOpt) -> io::Result<()>
+    {
+        if self.registration.is_none() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                "mock socket is not registered"));
+        }
+        self.registration = Some((token, interest, opts));
+        self.mock_loop = registering_loop();
+        Ok(())
+    }
+    pub fn deregister(&mut self) -> io::Result<()> {
+        self.mock_loop = None;
+        self.registration.take().map(|_| ()).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound,
+                "mock socket is not registered")
+        })
+    }
+    pub fn get(&self) -> Option<(mio::Token, EventSet, PollOpt)> {
+        self.registration
+    }
+    pub fn mock_loop(&self) -> Option<&Arc<Mutex<LoopState>>> {
+        self.mock_loop.as_ref()
+    }
+    // Marks the token ready in the `MockLoop` the socket is registered in
+    //
+    // Like epoll, reports only events of the interest, and hup and error
+    // events.
+    pub fn signal(&self, events: EventSet) {
+        let (token, interest, _) = match self.registration {
+            Some(reg) => reg,
+            None => return,
+        };
+        let events = events & (interest | EventSet::hup() | EventSet::error());
+        if events == EventSet::none() {
+            return;
+        }
+        if let Some(ref state) = self.mock_loop {
+            state.lock().expect("Poisoned MockLoop").add_ready(token, events);
+        }
+    }
+}
+
+impl fmt::Debug for Registration {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.registration.fmt(fmt)
+    }
+}
+
+fn loop_config() -> mio::EventLoopConfig {
+    let mut config = mio::EventLoopConfig::new();
+    config.timer_tick_ms(TIMER_TICK_MS);
+    config
+}
+
+// Deadline of a timeout for the timer with the tick in milliseconds
+fn round_deadline(now: Time, delay: u64, tick: u64) -> Time {
+    let deadline = now + Duration::from_millis(delay);
+    if tick == 0 {
+        return deadline;
+    }
+    let ticks = millis_since_zero(deadline).div_ceil(tick);
+    let min_ticks = millis_since_zero(now) / tick + 1;
+    Time::zero() + Duration::from_millis(cmp::max(ticks, min_ticks) * tick)
+}
+
+// `Time` has no accessor for its value, so it's found by a binary search
+fn millis_since_zero(time: Time) -> u64 {
+    let at = |ms| Time::zero() + Duration::from_millis(ms);
+    let mut high = 1;
+    while at(high) <= time {
+        high *= 2;
+    }
+    let mut low = 0;
+    // Invariant: at(low) <= time < at(high)
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if at(mid) <= time {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+// Lists operations with their time in milliseconds, for failure messages
+fn format_timeline(ops: &[(Time, Operation)]) -> String {
+    let mut buf = String::from("timeline:\n");
+    for &(time, ref op) in ops {
+        buf.push_str(&format!("  {:>8}ms {:?}\n",
+            millis_since_zero(time), op));
+    }
+    buf
+}
+
+// `TimerError` can't be constructed directly, so it's taken from the timer
+// of a loop which has capacity for a single timeout, which is used up
+fn full_timer() -> mio::EventLoop<Handler> {
+    let mut config = mio::EventLoopConfig::new();
+    config.timer_capacity(1);
+    config.timer_tick_ms(TIMER_TICK_MS);
+    let mut eloop = mio::EventLoop::configured(config)
+        .expect("event loop is created");
+    eloop.timeout_ms(0, 0)
+        .expect("timer has capacity for a timeout");
+    eloop
+}
+
+impl Handler {
+    fn event_loop(&mut self) -> &mut mio::EventLoop<Handler> {
+        self.event_loop.as_mut().expect("mock event loop is not polled")
+    }
+    // Returns a fresh error of the full timer
+    fn timer_overflow(&mut self) -> mio::TimerError {
+        self.full_timer.get_or_insert_with(full_timer)
+            .timeout_ms(0, 0).err()
+            .expect("timer is full")
+    }
+    fn push(&mut self, op: Operation) {
+        self.operations.push(op);
+        let now = self.state.lock().expect("Poisoned MockLoop").now;
+        self.stamps.push((next_seq(), now));
+    }
+    // Records the operation, returns an injected error if any
+    fn record(&mut self, op: Operation) -> io::Result<()> {
+        let idx = self.failures.iter().position(|(p, _)| p(&op));
+        self.push(op);
+        match idx {
+            Some(idx) => Err(self.failures.remove(idx).1),
+            None => Ok(()),
+        }
+    }
+    fn registering<F, R>(&mut self, f: F) -> R
+        where F: FnOnce(&mut mio::EventLoop<Handler>) -> R
+    {
+        REGISTERING.with(|r| *r.borrow_mut() = Some(self.state.clone()));
+        let result = f(self.event_loop());
+        REGISTERING.with(|r| *r.borrow_mut() = None);
+        result
+    }
 }
 
 impl mio::Handler for Handler {
-    type Timeout = _Timeo;
+    type Timeout = u64;
     type Message = _Notify;
+    fn notify(&mut self, _event_loop: &mut mio::EventLoop<Handler>,
+        msg: _Notify)
+    {
+        match msg {
+            _Notify::Fsm(token) => self.wakeups.push(token),
+        }
+    }
 }
 
 impl _LoopApi for Handler
 {
-    fn register(&mut self, _io: &mio::Evented, _token: mio::Token,
+    fn register(&mut self, io: &dyn mio::Evented, token: mio::Token,
         interest: EventSet, opt: PollOpt) -> io::Result<()>
     {
-        self.operations.push(Operation::Register(interest, opt));
-        Ok(())
+        self.record(Operation::Register(token, interest, opt))?;
+        self.registering(|l| l.register(io, token, interest, opt))
     }
 
-    fn reregister(&mut self, _io: &mio::Evented, _token: mio::Token,
+    fn reregister(&mut self, io: &dyn mio::Evented, token: mio::Token,
         interest: EventSet, opt: PollOpt) -> io::Result<()>
     {
-        self.operations.push(Operation::Reregister(interest, opt));
-        Ok(())
+        self.record(Operation::Reregister(token, interest, opt))?;
+        self.registering(|l| l.reregister(io, token, interest, opt))
     }
 
-    fn deregister(&mut self, _io: &mio::Evented) -> io::Result<()>
+    fn deregister(&mut self, io: &dyn mio::Evented) -> io::Result<()>
     {
-        self.operations.push(Operation::Deregister);
-        Ok(())
+        let token = self.token;
+        self.record(Operation::Deregister(token))?;
+        self.event_loop().deregister(io)
     }
 
-    fn timeout_ms(&mut self, _token: mio::Token, _delay: u64)
+    fn timeout_ms(&mut self, token: mio::Token, delay: u64)
         -> Result<mio::Timeout, mio::TimerError>
     {
-        panic!("Deprecated API");
+        if self.timeouts.len() >= self.timer_capacity {
+            return Err(self.timer_overflow());
+        }
+        // The real timer never fires, it's only used to get a `Timeout`
+        let id = self.next_timeout_id;
+        let timeout = self.event_loop().timeout_ms(id, 0)?;
+        self.next_timeout_id += 1;
+        let now = self.state.lock().expect("Poisoned MockLoop").now;
+        self.timeouts.push((id, timeout, PendingTimeout {
+            token,
+            delay_ms: delay,
+            deadline: round_deadline(now, delay, self.timer_tick),
+        }));
+        Ok(timeout)
+    }
+    fn clear_timeout(&mut self, timeout: mio::Timeout) -> bool
+    {
+        let token = self.token;
+        self.push(Operation::ClearTimeout(token));
+        if !self.event_loop().clear_timeout(timeout) {
+            return false;
+        }
+        // `mio::Timeout` can't be compared, so the timeout which was
+        // cleared is found by clearing pending ones until the timer says
+        // it's not there. The timeouts found in the timer are put back
+        // right away. The timer reuses the slot freed last, and the tick
+        // is always the same (see `TIMER_TICK_MS`), so the `Timeout` kept
+        // by the machine stays valid.
+        let mut cleared = None;
+        for idx in 0..self.timeouts.len() {
+            let (id, old, _) = self.timeouts[idx];
+            if self.event_loop().clear_timeout(old) {
+                self.timeouts[idx].1 = self.event_loop().timeout_ms(id, 0)
+                    .expect("timer has capacity for the timeout put back");
+            } else {
+                cleared = Some(id);
+                break;
+            }
+        }
+        let id = cleared.expect("cleared timeout is pending");
+        self.timeouts.retain(|&(x, _, _)| x != id);
+        true
+    }
+    fn shutdown(&mut self) {
+        let now = self.state.lock().expect("Poisoned MockLoop").now;
+        let reason = self.shutdown_reason.0.lock()
+            .expect("Poisoned ShutdownReason").take();
+        let token = self.token;
+        self.push(Operation::Shutdown(token, now, reason));
+        self.shutdown = true;
+    }
+}
+// Loop api of one of the scopes in `MockLoop::with_scopes()`
+struct TokenApi<'a, 'b: 'a> {
+    token: mio::Token,
+    handler: &'a RefCell<&'b mut Handler>,
+}
+
+impl<'a, 'b> TokenApi<'a, 'b> {
+    fn handler<'c>(&'c self) -> RefMut<'c, &'b mut Handler> {
+        let mut handler = self.handler.borrow_mut();
+        handler.token = self.token;
+        handler
+    }
+}
+
+impl<'a, 'b> _LoopApi for TokenApi<'a, 'b> {
+    fn register(&mut self, io: &dyn mio::Evented, token: mio::Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        self.handler().register(io, token, interest, opt)
     }
-    fn clear_timeout(&mut self, _token: mio::Timeout) -> bool
+    fn reregister(&mut self, io: &dyn mio::Evented, token: mio::Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
     {
-        panic!("Deprecated API");
+        self.handler().reregister(io, token, interest, opt)
+    }
+    fn deregister(&mut self, io: &dyn mio::Evented) -> io::Result<()> {
+        self.handler().deregister(io)
+    }
+    fn timeout_ms(&mut self, token: mio::Token, delay: u64)
+        -> Result<mio::Timeout, mio::TimerError>
+    {
+        self.handler().timeout_ms(token, delay)
+    }
+    fn clear_timeout(&mut self, timeout: mio::Timeout) -> bool {
+        self.handler().clear_timeout(timeout)
     }
     fn shutdown(&mut self) {
-        self.operations.push(Operation::Shutdown);
+        self.handler().shutdown()
     }
 }
 
 #[cfg(test)]
 mod self_test {
 
-    use rotor::{Machine, EventSet, Scope, Response};
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+    use std::cell::Cell;
+    use std::time::Duration;
+    use rotor::{Machine, EventSet, PollOpt, Scope, Response, Time};
+    use rotor::{EarlyScope, WakeupError};
+    use rotor::mio::Token;
     use rotor::void::{unreachable, Void};
-    use super::MockLoop;
+    use stream::{MemIo, IoEvent};
+    use super::{MockLoop, Operation, PendingTimeout, LoopEvent};
+    use expect::Expected;
+    use context::{ComposedCtx, DynContext};
 
     #[derive(PartialEq, Eq, Debug)]
     struct M(u32);
@@ -131,7 +1292,7 @@ mod self_test {
         }
         fn timeout(self, _scope: &mut Scope<()>) -> Response<Self, Self::Seed>
         {
-            unimplemented!();
+            Response::ok(M(self.0 + 100))
         }
         fn wakeup(self, _scope: &mut Scope<()>) -> Response<Self, Self::Seed>
         {
@@ -139,6 +1300,819 @@ mod self_test {
         }
     }
 
+    #[derive(PartialEq, Eq, Debug)]
+    struct Parent(u32);
+
+    impl Machine for Parent {
+        type Context = ();
+        type Seed = u32;
+        fn create(seed: u32, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            Response::ok(Parent(seed))
+        }
+        fn ready(self, _events: EventSet, _scope: &mut Scope<()>)
+            -> Response<Self, u32>
+        {
+            unimplemented!();
+        }
+        fn spawned(self, _scope: &mut Scope<()>) -> Response<Self, u32> {
+            if self.0 == 1 {
+                Response::spawn(Parent(2), 20)
+            } else {
+                Response::ok(self)
+            }
+        }
+        fn timeout(self, _scope: &mut Scope<()>) -> Response<Self, u32> {
+            Response::done()
+        }
+        fn wakeup(self, _scope: &mut Scope<()>) -> Response<Self, u32> {
+            let seed = self.0 + 10;
+            Response::spawn(self, seed)
+        }
+    }
+
+    #[derive(PartialEq, Eq, Debug)]
+    struct Events(Vec<EventSet>);
+
+    impl Machine for Events {
+        type Context = ();
+        type Seed = Void;
+        fn create(seed: Void, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            unreachable(seed)
+        }
+        fn ready(mut self, events: EventSet, _scope: &mut Scope<()>)
+            -> Response<Self, Void>
+        {
+            self.0.push(events);
+            Response::ok(self)
+        }
+        fn spawned(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            unimplemented!();
+        }
+        fn timeout(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            unimplemented!();
+        }
+        fn wakeup(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            unimplemented!();
+        }
+    }
+
+    #[test]
+    fn spawn() {
+        let mut factory = MockLoop::new(());
+        let resp = Parent(1).wakeup(&mut factory.scope(3));
+        let (parent, children) = factory.handle_spawn(3, resp);
+        assert_eq!(parent.expect_machine(), Parent(2));
+        let children: Vec<_> = children.into_iter()
+            .map(|(token, resp)| (token, resp.expect_machine())).collect();
+        assert_eq!(children, vec![(Token(4), Parent(11)),
+                                  (Token(5), Parent(20))]);
+        assert_eq!(factory.spawned(), &[Token(4), Token(5)]);
+        let resp = Response::ok(Parent(7));
+        let (parent, children) = factory.handle_spawn(3, resp);
+        assert_eq!(parent.expect_machine(), Parent(7));
+        assert_eq!(children.len(), 0);
+    }
+
+    #[test]
+    fn dispatch() {
+        let mut factory = MockLoop::new(());
+        let token = factory.add_machine(|_| Response::ok(Parent(1))).unwrap();
+        assert_eq!(token, Token(0));
+        factory.dispatch_wakeup::<Parent>(token);
+        assert_eq!(factory.machine(token), Some(&Parent(2)));
+        assert_eq!(factory.machine(Token(1)), Some(&Parent(11)));
+        assert_eq!(factory.machine(Token(2)), Some(&Parent(20)));
+        factory.dispatch_timeout::<Parent>(Token(1));
+        assert!(!factory.has_machine(Token(1)));
+        factory.insert_machine(Token(7), M(1));
+        factory.dispatch_wakeup::<M>(Token(7));
+        assert_eq!(factory.machine(Token(7)), Some(&M(2)));
+        assert_eq!(factory.machine::<Parent>(Token(7)), None);
+    }
+
+    #[test]
+    fn spurious_ready() {
+        let mut factory = MockLoop::new(());
+        let io = MemIo::new();
+        let token = factory.add_machine(|scope| {
+            scope.register(&io, EventSet::readable(), PollOpt::level())
+                .unwrap();
+            Response::ok(Events(Vec::new()))
+        }).unwrap();
+        assert_eq!(factory.dispatch_spurious::<Events>(token),
+                   EventSet::writable());
+        factory.scope(token.0).reregister(&io,
+            EventSet::readable() | EventSet::writable(), PollOpt::level())
+            .unwrap();
+        assert_eq!(factory.dispatch_spurious::<Events>(token),
+                   EventSet::none());
+        assert_eq!(factory.machine(token), Some(&Events(vec![
+            EventSet::writable(), EventSet::none()])));
+    }
+
+    #[test]
+    #[should_panic(expected="Token(0) stopped with pending timeouts")]
+    fn cleanup_check() {
+        let mut factory = MockLoop::new(());
+        factory.set_check_cleanup(true);
+        let io = MemIo::new();
+        let token = factory.add_machine(|scope| {
+            scope.register(&io, EventSet::readable(), PollOpt::level())
+                .unwrap();
+            scope.timeout_ms(100).unwrap();
+            Response::ok(Parent(1))
+        }).unwrap();
+        factory.scope(0).deregister(&io).unwrap();
+        factory.dispatch_timeout::<Parent>(token);
+    }
+
+    #[test]
+    fn allocate_token() {
+        let mut factory = MockLoop::new(());
+        assert_eq!(factory.allocate_token(), Token(0));
+        assert_eq!(factory.allocate_token(), Token(1));
+        factory.scope(10);
+        assert_eq!(factory.allocate_token(), Token(11));
+        factory.scope(3);
+        assert_eq!(factory.allocate_token(), Token(12));
+    }
+
+    #[test]
+    fn register_mem_io() {
+        let mut factory = MockLoop::new(());
+        let io = MemIo::new();
+        factory.scope(7).register(&io, EventSet::readable(), PollOpt::level())
+            .unwrap();
+        assert_eq!(io.registration(),
+            Some((Token(7), EventSet::readable(), PollOpt::level())));
+        assert!(factory.scope(7).register(&io, EventSet::readable(),
+            PollOpt::level()).is_err());
+        factory.scope(7).deregister(&io).unwrap();
+        assert_eq!(io.registration(), None);
+        let err = factory.scope(7).deregister(&io).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = factory.scope(7).reregister(&io, EventSet::readable(),
+            PollOpt::level()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn operations() {
+        let mut factory = MockLoop::new(());
+        let io = MemIo::new();
+        factory.scope(1).register(&io, EventSet::readable(), PollOpt::edge())
+            .unwrap();
+        factory.scope(2).deregister(&io).unwrap();
+        assert!(!factory.is_shutdown_requested());
+        factory.scope(3).shutdown_loop();
+        assert_eq!(factory.take_operations(), vec![
+            Operation::Register(Token(1), EventSet::readable(),
+                                PollOpt::edge()),
+            Operation::Deregister(Token(2)),
+            Operation::Shutdown(Token(3), Time::zero(), None),
+        ]);
+        assert_eq!(factory.operations(), &[]);
+        factory.scope(2).shutdown_loop();
+        factory.scope(3).shutdown_loop();
+        assert_eq!(factory.ops_for(Token(2)), vec![
+            Operation::Shutdown(Token(2), Time::zero(), None),
+        ]);
+        factory.clear_operations();
+        assert_eq!(factory.operations(), &[]);
+        assert!(factory.is_shutdown_requested());
+    }
+
+    #[test]
+    fn operation_tokens() {
+        let mut factory = MockLoop::new(());
+        let io1 = MemIo::new();
+        let io2 = MemIo::new();
+        factory.scope(1).register(&io1, EventSet::readable(),
+            PollOpt::edge()).unwrap();
+        factory.scope(2).register(&io2, EventSet::writable(),
+            PollOpt::edge()).unwrap();
+        factory.scope(2).deregister(&io2).unwrap();
+        factory.scope(1).shutdown_loop();
+        let tokens: Vec<_> = factory.operations().iter()
+            .map(|op| op.token()).collect();
+        assert_eq!(tokens, vec![Token(1), Token(2), Token(2), Token(1)]);
+    }
+
+    #[test]
+    fn shutdown_requested() {
+        let mut factory = MockLoop::new(());
+        factory.scope(1).timeout_ms(100).unwrap();
+        assert!(!factory.is_shutdown_requested());
+        factory.scope(2).shutdown_loop();
+        assert!(factory.is_shutdown_requested());
+        factory.take_operations();
+        assert!(factory.is_shutdown_requested());
+    }
+
+    #[test]
+    fn ops_for() {
+        let mut factory = MockLoop::new(());
+        let io1 = MemIo::new();
+        let io2 = MemIo::new();
+        factory.scope(1).register(&io1, EventSet::readable(),
+            PollOpt::edge()).unwrap();
+        factory.scope(2).register(&io2, EventSet::readable(),
+            PollOpt::level()).unwrap();
+        factory.scope(1).reregister(&io1, EventSet::writable(),
+            PollOpt::edge()).unwrap();
+        factory.scope(2).deregister(&io2).unwrap();
+        assert_eq!(factory.ops_for(Token(1)), vec![
+            Operation::Register(Token(1), EventSet::readable(),
+                                PollOpt::edge()),
+            Operation::Reregister(Token(1), EventSet::writable(),
+                                  PollOpt::edge()),
+        ]);
+        assert_eq!(factory.ops_for(Token(2)), vec![
+            Operation::Register(Token(2), EventSet::readable(),
+                                PollOpt::level()),
+            Operation::Deregister(Token(2)),
+        ]);
+        assert_eq!(factory.ops_for(Token(3)), vec![]);
+    }
+
+    #[test]
+    fn clear_operations() {
+        let mut factory = MockLoop::new(());
+        let io = MemIo::new();
+        factory.scope(1).register(&io, EventSet::readable(),
+            PollOpt::edge()).unwrap();
+        assert_eq!(factory.operations().len(), 1);
+        factory.clear_operations();
+        factory.scope(1).reregister(&io, EventSet::writable(),
+            PollOpt::edge()).unwrap();
+        assert_eq!(factory.operations(), &[
+            Operation::Reregister(Token(1), EventSet::writable(),
+                                  PollOpt::edge()),
+        ]);
+        assert_eq!(factory.sequenced_operations().len(), 1);
+    }
+
+    fn early_create(scope: &mut EarlyScope) -> Response<M, Void> {
+        let io = MemIo::new();
+        scope.register(&io, EventSet::readable(), PollOpt::edge()).unwrap();
+        scope.notifier().wakeup().unwrap();
+        Response::ok(M(0))
+    }
+
+    #[test]
+    fn early_scope() {
+        let mut factory = MockLoop::new(());
+        let machine = early_create(&mut factory.early_scope(4));
+        assert_eq!(machine.expect_machine(), M(0));
+        assert_eq!(factory.take_operations(), vec![
+            Operation::Register(Token(4), EventSet::readable(),
+                                PollOpt::edge()),
+        ]);
+        assert_eq!(factory.pending_wakeups(), vec![Token(4)]);
+    }
+
+    #[test]
+    fn two_scopes() {
+        let mut factory = MockLoop::new(Rc::new(Cell::new(0)));
+        let io = MemIo::new();
+        factory.with_scopes(1, 2, |a, b| {
+            a.register(&io, EventSet::readable(), PollOpt::level()).unwrap();
+            b.notifier().wakeup().unwrap();
+            b.shutdown_loop();
+            a.set(a.get() + 1);
+            b.set(b.get() + 1);
+        });
+        assert_eq!(factory.ctx().get(), 2);
+        assert_eq!(factory.take_operations(), vec![
+            Operation::Register(Token(1), EventSet::readable(),
+                                PollOpt::level()),
+            Operation::Shutdown(Token(2), Time::zero(), None),
+        ]);
+        assert_eq!(factory.pending_wakeups(), vec![Token(2)]);
+        assert_eq!(factory.allocate_token(), Token(3));
+    }
+
+    #[test]
+    fn two_scopes_context() {
+        let mut factory = MockLoop::new((1u32, Rc::new(Cell::new(1))));
+        factory.with_scopes(1, 2, |_, b| {
+            b.0 += 1;
+            b.1.set(b.1.get() + 1);
+        });
+        assert_eq!(factory.ctx().0, 1);
+        assert_eq!(factory.ctx().1.get(), 2);
+        factory.with_scopes(1, 2, |a, _| a.0 += 1);
+        assert_eq!(factory.ctx().0, 2);
+    }
+
+    #[test]
+    fn composed_ctx() {
+        let mut factory = MockLoop::new(ComposedCtx(1u32, "second"));
+        *factory.scope_first(1) += 1;
+        assert_eq!(*factory.scope_second(2), "second");
+        factory.scope_second(2).shutdown_loop();
+        assert_eq!(factory.ctx().first(), &mut 2);
+        assert_eq!(factory.ops_for(Token(2)), vec![
+            Operation::Shutdown(Token(2), Time::zero(), None),
+        ]);
+        let mut factory = MockLoop::new(Box::new(5u32));
+        *factory.scope_as::<u32>(1) += 1;
+        assert_eq!(**factory.ctx(), 6);
+    }
+
+    trait Counter {
+        fn increment(&mut self);
+        fn value(&self) -> u32;
+    }
+
+    struct Count(u32);
+
+    impl Counter for Count {
+        fn increment(&mut self) {
+            self.0 += 1;
+        }
+        fn value(&self) -> u32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn dyn_context() {
+        let ctx: Box<dyn Counter> = Box::new(Count(0));
+        let mut factory = MockLoop::new(DynContext::new(ctx));
+        factory.with_dyn_scope::<dyn Counter, _, _>(1, |scope| {
+            scope.increment();
+            scope.shutdown_loop();
+        });
+        assert_eq!(factory.ctx().value(), 1);
+        assert_eq!(factory.ops_for(Token(1)), vec![
+            Operation::Shutdown(Token(1), Time::zero(), None),
+        ]);
+    }
+
+    #[test]
+    fn replace_ctx() {
+        let mut factory = MockLoop::new(1);
+        factory.scope(1).shutdown_loop();
+        *factory.ctx() += 1;
+        assert_eq!(factory.replace_ctx(10), 2);
+        factory.set_ctx(20);
+        assert_eq!(*factory.scope(1), 20);
+        assert_eq!(factory.operations().len(), 1);
+    }
+
+    #[test]
+    fn interests() {
+        let mut factory = MockLoop::new(());
+        let io = MemIo::new();
+        let r = EventSet::readable();
+        let rw = EventSet::readable() | EventSet::writable();
+        factory.scope(1).register(&io, r, PollOpt::level()).unwrap();
+        factory.scope(2).shutdown_loop();
+        factory.scope(1).reregister(&io, rw, PollOpt::level()).unwrap();
+        factory.scope(1).reregister(&io, r, PollOpt::level()).unwrap();
+        factory.scope(1).deregister(&io).unwrap();
+        factory.assert_interests(Token(1), &[r, rw, r, EventSet::none()]);
+        assert_eq!(factory.interests(Token(2)), vec![]);
+    }
+
+    #[test]
+    fn shutdown_reason() {
+        let mut factory = MockLoop::new(None);
+        *factory.ctx() = Some(factory.shutdown_reason());
+        factory.advance(Duration::from_millis(10));
+        factory.scope(1).as_ref().unwrap().set("fatal error");
+        factory.scope(1).shutdown_loop();
+        factory.scope(2).shutdown_loop();
+        let at = Time::zero() + Duration::from_millis(10);
+        assert_eq!(factory.take_operations(), vec![
+            Operation::Shutdown(Token(1), at, Some("fatal error".into())),
+            Operation::Shutdown(Token(2), at, None),
+        ]);
+    }
+
+    #[test]
+    fn debug() {
+        let mut factory = MockLoop::new("context");
+        factory.scope(1).shutdown_loop();
+        factory.scope(2).timeout_ms(10).unwrap();
+        let text = format!("{:?}", factory);
+        assert!(text.starts_with("MockLoop { now: "));
+        assert!(text.contains(
+            "operations: [Shutdown(Token(1), Time(1), None)]"));
+        assert!(text.contains("delay_ms: 10"));
+        assert!(text.ends_with("context: \"context\" }"));
+    }
+
+    #[test]
+    fn expectations() {
+        let mut factory = MockLoop::new(());
+        let io = MemIo::new();
+        factory.expect(&[
+            Expected::Register(EventSet::readable(), PollOpt::edge()),
+            Expected::Reregister(EventSet::writable(), PollOpt::edge()),
+        ]);
+        factory.scope(1).register(&io, EventSet::readable(), PollOpt::edge())
+            .unwrap();
+        factory.scope(1).reregister(&io, EventSet::writable(),
+            PollOpt::edge()).unwrap();
+        factory.verify();
+        assert_eq!(factory.operations(), &[]);
+    }
+
+    #[test]
+    #[should_panic(expected="operations don't match expectations")]
+    fn unmet_expectations() {
+        let mut factory = MockLoop::new(());
+        factory.expect(&[Expected::Deregister]);
+        factory.scope(1).shutdown_loop();
+        factory.verify();
+    }
+
+    #[test]
+    fn failed_registration() {
+        let mut factory = MockLoop::new(());
+        let io = MemIo::new();
+        factory.fail_operation(
+            |op| matches!(*op, Operation::Register(Token(1), _, _)),
+            io::Error::from_raw_os_error(1));
+        let err = factory.scope(1).register(&io, EventSet::readable(),
+            PollOpt::level()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(1));
+        assert_eq!(io.registration(), None);
+        factory.scope(1).register(&io, EventSet::readable(),
+            PollOpt::level()).unwrap();
+        assert_eq!(factory.operations().len(), 2);
+    }
+
+    #[test]
+    fn virtual_clock() {
+        let mut factory = MockLoop::new(());
+        let mut io = MemIo::new();
+        factory.scope(1).register(&io, EventSet::readable(), PollOpt::edge())
+            .unwrap();
+        assert_eq!(factory.scope(1).now(), Time::zero());
+        factory.advance(Duration::from_millis(1500));
+        assert_eq!(factory.scope(1).now(),
+                   Time::zero() + Duration::from_millis(1500));
+        assert_eq!(io.write(b"x").unwrap(), 1);
+        factory.set_time(Time::zero() + Duration::from_secs(2));
+        assert_eq!(factory.now(), Time::zero() + Duration::from_secs(2));
+        assert_eq!(io.timed_timeline(), vec![
+            (Time::zero() + Duration::from_millis(1500),
+             IoEvent::Write(b"x".to_vec())),
+        ]);
+    }
+
+    #[test]
+    fn stream_follows_loop_clock() {
+        let mut factory = MockLoop::new(());
+        let at = |ms| Time::zero() + Duration::from_millis(ms);
+        let mut io = MemIo::new();
+        factory.scope(1).register(&io, EventSet::readable(), PollOpt::edge())
+            .unwrap();
+        io.push_bytes_after(Duration::from_millis(100), "hello");
+        io.set_write_latency(Duration::from_millis(50));
+        io.set_write_deadline(Some(at(200)));
+        factory.advance(Duration::from_millis(100));
+        assert_eq!(factory.take_ready_tokens(),
+                   vec![(Token(1), EventSet::readable())]);
+        let mut buf = [0u8; 16];
+        assert_eq!(io.read(&mut buf).unwrap(), 5);
+        assert_eq!(io.write(b"world").unwrap(), 5);
+        assert_eq!(io.output_bytes(), b"");
+        factory.advance(Duration::from_millis(50));
+        assert_eq!(io.output_bytes(), b"world");
+        factory.advance(Duration::from_millis(50));
+        assert_eq!(io.write(b"x").unwrap_err().kind(),
+                   io::ErrorKind::TimedOut);
+        assert_eq!(io.timed_timeline(), vec![
+            (at(100), IoEvent::Read(5)),
+            (at(100), IoEvent::Write(b"world".to_vec())),
+            (at(200), IoEvent::WriteError(io::ErrorKind::TimedOut)),
+        ]);
+    }
+
+    #[test]
+    fn sequence() {
+        let mut factory = MockLoop::new(());
+        let mut io = MemIo::new();
+        factory.scope(1).register(&io, EventSet::readable(), PollOpt::edge())
+            .unwrap();
+        assert_eq!(io.write(b"x").unwrap(), 1);
+        factory.scope(1).reregister(&io, EventSet::writable(),
+            PollOpt::edge()).unwrap();
+        let ops = factory.sequenced_operations();
+        let io_events = io.sequenced_timeline();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(io_events.len(), 1);
+        assert_eq!(io_events[0].1, IoEvent::Write(b"x".to_vec()));
+        assert!(ops[0].0 < io_events[0].0);
+        assert!(io_events[0].0 < ops[1].0);
+        factory.clear_operations();
+        assert_eq!(factory.sequenced_operations(), vec![]);
+    }
+
+    #[test]
+    fn timed_operations() {
+        let mut factory = MockLoop::new(());
+        let io = MemIo::new();
+        factory.scope(1).register(&io, EventSet::readable(), PollOpt::edge())
+            .unwrap();
+        factory.advance(Duration::from_millis(100));
+        factory.scope(1).deregister(&io).unwrap();
+        assert_eq!(factory.timed_operations(), vec![
+            (Time::zero(), Operation::Register(Token(1),
+                EventSet::readable(), PollOpt::edge())),
+            (Time::zero() + Duration::from_millis(100),
+             Operation::Deregister(Token(1))),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected="timeline:\n         0ms Register(Token(1), \
+                             Readable, Edge-Triggered)\n       \
+                             100ms Deregister")]
+    fn verify_timeline() {
+        let mut factory = MockLoop::new(());
+        let io = MemIo::new();
+        factory.scope(1).register(&io, EventSet::readable(), PollOpt::edge())
+            .unwrap();
+        factory.advance(Duration::from_millis(100));
+        factory.scope(1).deregister(&io).unwrap();
+        factory.expect(&[Expected::Deregister]);
+        factory.verify();
+    }
+
+    #[test]
+    fn timeouts() {
+        let mut factory = MockLoop::new(());
+        factory.advance(Duration::from_secs(1));
+        let long = factory.scope(1).timeout_ms(5000).unwrap();
+        factory.scope(2).timeout_ms(100).unwrap();
+        assert_eq!(factory.pending_timeouts(), vec![
+            PendingTimeout {
+                token: Token(2),
+                delay_ms: 100,
+                deadline: Time::zero() + Duration::from_millis(1100),
+            },
+            PendingTimeout {
+                token: Token(1),
+                delay_ms: 5000,
+                deadline: Time::zero() + Duration::from_millis(6000),
+            },
+        ]);
+        factory.set_timer_capacity(2);
+        assert!(factory.scope(3).timeout_ms(100).is_err());
+        assert!(factory.scope(1).clear_timeout(long));
+        assert!(!factory.scope(1).clear_timeout(long));
+        assert!(factory.scope(3).timeout_ms(100).is_ok());
+        assert!(factory.scope(3).timeout_ms(100).is_err());
+        assert_eq!(factory.pending_timeouts().len(), 2);
+        assert_eq!(factory.operations(), &[
+            Operation::ClearTimeout(Token(1)),
+            Operation::ClearTimeout(Token(1)),
+        ]);
+    }
+
+    #[test]
+    fn clear_timeout_recorded() {
+        let mut factory = MockLoop::new(());
+        let timeout = factory.scope(3).timeout_ms(100).unwrap();
+        factory.scope(1).timeout_ms(100).unwrap();
+        assert!(factory.scope(3).clear_timeout(timeout));
+        assert_eq!(factory.operations(), &[
+            Operation::ClearTimeout(Token(3)),
+        ]);
+        assert_eq!(factory.timeouts_for(Token(3)), vec![]);
+        assert_eq!(factory.timeouts_for(Token(1)).len(), 1);
+    }
+
+    #[test]
+    fn clear_timeouts_in_any_order() {
+        let mut factory = MockLoop::new(());
+        let delays = |f: &MockLoop<()>| -> Vec<u64> {
+            f.pending_timeouts().iter().map(|t| t.delay_ms).collect()
+        };
+        let a = factory.scope(1).timeout_ms(100).unwrap();
+        let b = factory.scope(1).timeout_ms(200).unwrap();
+        assert_eq!(factory.pending_wakeups(), vec![]);
+        let c = factory.scope(2).timeout_ms(300).unwrap();
+        let d = factory.scope(1).timeout_ms(400).unwrap();
+        assert!(factory.scope(2).clear_timeout(c));
+        assert_eq!(delays(&factory), vec![100, 200, 400]);
+        assert!(factory.scope(1).clear_timeout(b));
+        assert!(!factory.scope(1).clear_timeout(c));
+        assert!(!factory.scope(1).clear_timeout(b));
+        assert_eq!(delays(&factory), vec![100, 400]);
+        factory.fire_timeout(1, M(1));
+        assert!(!factory.scope(1).clear_timeout(a));
+        assert!(factory.scope(1).clear_timeout(d));
+        assert_eq!(delays(&factory), vec![]);
+    }
+
+    #[test]
+    fn many_timeouts_per_token() {
+        let mut factory = MockLoop::new(());
+        let at = |ms| Time::zero() + Duration::from_millis(ms);
+        factory.scope(1).timeout_ms(200).unwrap();
+        factory.scope(1).timeout_ms(100).unwrap();
+        factory.scope(2).timeout_ms(150).unwrap();
+        let deadlines: Vec<_> = factory.timeouts_for(Token(1)).iter()
+            .map(|t| t.deadline).collect();
+        assert_eq!(deadlines, vec![at(100), at(200)]);
+        let mut value = None;
+        factory.fire_timeout(1, M(1)).wrap(|x| value = Some(x));
+        assert_eq!(value, Some(M(101)));
+        assert_eq!(factory.now(), at(100));
+        factory.advance(Duration::from_millis(100));
+        let mut fired = Vec::new();
+        factory.fire_due_timeouts(|token, scope| {
+            fired.push((token, scope.now()));
+        });
+        assert_eq!(fired, vec![(Token(2), at(200)), (Token(1), at(200))]);
+        assert_eq!(factory.timeouts_for(Token(1)), vec![]);
+    }
+
+    #[test]
+    fn timer_jitter() {
+        let mut factory = MockLoop::new(());
+        let at = |ms| Time::zero() + Duration::from_millis(ms);
+        factory.set_timer_jitter(Duration::from_millis(3));
+        factory.scope(1).timeout_ms(100).unwrap();
+        factory.scope(2).timeout_ms(200).unwrap();
+        factory.scope(3).timeout_ms(300).unwrap();
+        factory.fire_timeout(1, M(1));
+        assert_eq!(factory.now(), at(103));
+        factory.advance(Duration::from_millis(99));
+        let mut fired = Vec::new();
+        factory.fire_due_timeouts(|token, _| fired.push(token));
+        assert_eq!(fired, vec![]);
+        factory.run_for(Duration::from_millis(200), |token, _, scope| {
+            fired.push(token);
+            assert_eq!(scope.now(), at(100 * token.0 as u64 + 3));
+        });
+        assert_eq!(fired, vec![Token(2), Token(3)]);
+    }
+
+    #[test]
+    fn scheduled_wakeups() {
+        let mut factory = MockLoop::new(());
+        let at = |ms| Time::zero() + Duration::from_millis(ms);
+        factory.scope(1).timeout_ms(100).unwrap();
+        factory.schedule_wakeup(Token(2), at(50));
+        factory.schedule_wakeup(Token(3), at(100));
+        factory.schedule_wakeup(Token(4), at(300));
+        let mut events = Vec::new();
+        factory.run_for(Duration::from_millis(150), |token, event, scope| {
+            events.push((token, event, scope.now()));
+        });
+        assert_eq!(events, vec![
+            (Token(2), LoopEvent::Wakeup, at(50)),
+            (Token(3), LoopEvent::Wakeup, at(100)),
+            (Token(1), LoopEvent::Timeout, at(100)),
+        ]);
+        assert_eq!(factory.now(), at(150));
+        factory.advance(Duration::from_millis(150));
+        assert_eq!(factory.pending_wakeups(), vec![Token(4)]);
+    }
+
+    #[test]
+    fn timer_resolution() {
+        let mut factory = MockLoop::new(());
+        factory.set_timer_resolution(Duration::from_millis(100));
+        factory.scope(1).timeout_ms(150).unwrap();
+        factory.advance(Duration::from_millis(30));
+        factory.scope(2).timeout_ms(0).unwrap();
+        factory.scope(3).timeout_ms(70).unwrap();
+        factory.scope(4).timeout_ms(71).unwrap();
+        let deadlines: Vec<_> = factory.pending_timeouts().iter()
+            .map(|t| (t.token, t.deadline)).collect();
+        let at = |ms| Time::zero() + Duration::from_millis(ms);
+        assert_eq!(deadlines, vec![
+            (Token(2), at(100)),
+            (Token(3), at(100)),
+            (Token(1), at(200)),
+            (Token(4), at(200)),
+        ]);
+    }
+
+    #[test]
+    fn fire_timeouts() {
+        let mut factory = MockLoop::new(());
+        factory.scope(1).timeout_ms(100).unwrap();
+        factory.scope(2).timeout_ms(200).unwrap();
+        factory.scope(3).timeout_ms(300).unwrap();
+        let mut value = None;
+        factory.fire_timeout(2, M(1)).wrap(|x| value = Some(x));
+        assert_eq!(value, Some(M(101)));
+        assert_eq!(factory.now(), Time::zero() + Duration::from_millis(200));
+        let mut fired = Vec::new();
+        factory.fire_due_timeouts(|token, _scope| fired.push(token));
+        assert_eq!(fired, vec![Token(1)]);
+        assert_eq!(factory.pending_timeouts().len(), 1);
+    }
+
+    #[test]
+    fn wakeups() {
+        let mut factory = MockLoop::new(());
+        let notifier = factory.scope(5).notifier();
+        assert_eq!(factory.pending_wakeups(), vec![]);
+        notifier.wakeup().unwrap();
+        factory.scope(2).notifier().wakeup().unwrap();
+        assert_eq!(factory.pending_wakeups(), vec![Token(5), Token(2)]);
+        let mut value = None;
+        factory.deliver_wakeup(2, M(1)).wrap(|x| value = Some(x));
+        assert_eq!(value, Some(M(2)));
+        let mut tokens = Vec::new();
+        factory.process_wakeups(|token, scope| {
+            // Wakeup sent while processing is delivered too
+            if tokens.is_empty() {
+                scope.notifier().wakeup().unwrap();
+            }
+            tokens.push(token);
+        });
+        assert_eq!(tokens, vec![Token(5), Token(5)]);
+        assert_eq!(factory.pending_wakeups(), vec![]);
+    }
+
+    #[test]
+    fn deliver_notifications() {
+        use std::thread;
+
+        let mut factory = MockLoop::new(());
+        let notifier = factory.scope(4).notifier();
+        thread::spawn(move || notifier.wakeup().unwrap()).join().unwrap();
+        let mut delivered = Vec::new();
+        factory.process_wakeups(|token, scope| {
+            M(1).wakeup(scope).wrap(|m| delivered.push((token, m)));
+        });
+        assert_eq!(delivered, vec![(Token(4), M(2))]);
+    }
+
+    #[test]
+    fn stale_notifier() {
+        let notifier = {
+            let mut factory = MockLoop::new(());
+            factory.scope(1).notifier()
+        };
+        let mut factory = MockLoop::new(());
+        notifier.wakeup().ok();
+        assert_eq!(factory.pending_wakeups(), vec![]);
+        factory.scope(2).notifier().wakeup().unwrap();
+        assert_eq!(factory.pending_wakeups(), vec![Token(2)]);
+    }
+
+    #[test]
+    fn notify_queue_full() {
+        let mut factory = MockLoop::with_notify_capacity((), 2);
+        let notifier = factory.scope(1).notifier();
+        notifier.wakeup().unwrap();
+        notifier.wakeup().unwrap();
+        match notifier.wakeup() {
+            Err(WakeupError::Full) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(factory.pending_wakeups(), vec![Token(1), Token(1)]);
+        notifier.wakeup().unwrap();
+    }
+
+    #[test]
+    fn readiness_from_pushes() {
+        let mut factory = MockLoop::new(());
+        let mut io = MemIo::new();
+        factory.scope(3).register(&io, EventSet::readable(), PollOpt::level())
+            .unwrap();
+        assert_eq!(factory.ready_tokens(), vec![]);
+        io.push_bytes("hello");
+        assert_eq!(factory.take_ready_tokens(),
+                   vec![(Token(3), EventSet::readable())]);
+        io.shutdown_input();
+        assert_eq!(factory.take_ready_tokens(),
+                   vec![(Token(3), EventSet::readable() | EventSet::hup())]);
+    }
+
+    #[test]
+    fn readiness_on_register() {
+        let mut factory = MockLoop::new(());
+        let mut io = MemIo::new();
+        io.push_bytes("hello");
+        factory.scope(3).register(&io, EventSet::readable(), PollOpt::edge())
+            .unwrap();
+        assert_eq!(factory.take_ready_tokens(),
+                   vec![(Token(3), EventSet::readable())]);
+        io.shutdown_input();
+        factory.take_ready_tokens();
+        factory.scope(3).reregister(&io, EventSet::readable(),
+            PollOpt::edge()).unwrap();
+        assert_eq!(factory.take_ready_tokens(),
+                   vec![(Token(3), EventSet::readable() | EventSet::hup())]);
+        let empty = MemIo::new();
+        factory.scope(4).register(&empty, EventSet::readable(),
+            PollOpt::edge()).unwrap();
+        assert_eq!(factory.ready_tokens(), vec![]);
+    }
+
     #[test]
     fn test_machine() {
         let mut factory = MockLoop::new(());
@@ -0,0 +1,13 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the next number of the sequence shared by all mock loops and
+/// streams
+///
+/// Operations of `MockLoop` and I/O events of `MemIo` are stamped with it,
+/// so their relative order can be checked.
+pub fn next_seq() -> u64 {
+    SEQUENCE.fetch_add(1, Ordering::SeqCst) as u64 + 1
+}
@@ -0,0 +1,224 @@
+use std::io;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use rotor::mio;
+use rotor_stream::SocketError;
+
+use stream::{MemIo, IoEvent};
+
+
+/// A handle to read output of the application from `MemIo`
+///
+/// Created by `MemIo::split()`. Note: "reader" here is from the point of
+/// view of the test, it reads what the application has written.
+#[derive(Clone, Debug)]
+pub struct MemReader(MemIo);
+
+/// A handle to feed input into `MemIo`
+///
+/// Created by `MemIo::split()`. Note: "writer" here is from the point of
+/// view of the test, it writes what the application will read.
+#[derive(Clone, Debug)]
+pub struct MemWriter(MemIo);
+
+/// The application side of the stream created by `MemIo::endpoints()`
+///
+/// It's only possible to read input and write output here. The handle
+/// implements `StreamSocket` so it can be passed to `rotor_stream`.
+#[derive(Debug)]
+pub struct AppSide(MemIo);
+
+/// The test side of the stream created by `MemIo::endpoints()`
+///
+/// It's only possible to push input and inspect output here.
+#[derive(Clone, Debug)]
+pub struct TestSide(MemIo);
+
+impl MemIo {
+    /// Split off test-side handles for the input and the output
+    ///
+    /// The handles may be moved to different helpers or threads. The stream
+    /// itself should be fed to the application.
+    pub fn split(&self) -> (MemReader, MemWriter) {
+        (MemReader(self.clone()), MemWriter(self.clone()))
+    }
+    /// Create a stream with separate handles for the application and the
+    /// test
+    ///
+    /// Unlike with a cloned `MemIo` the test can't accidentally read the
+    /// application's input or write into the application's output.
+    pub fn endpoints() -> (AppSide, TestSide) {
+        let io = MemIo::new();
+        (AppSide(io.clone()), TestSide(io))
+    }
+}
+
+impl MemReader {
+    /// Get output as a string (see `MemIo::output_str`)
+    pub fn output_str(&self) -> String {
+        self.0.output_str()
+    }
+    /// Get data in the output buffer (see `MemIo::output_bytes`)
+    pub fn output_bytes(&self) -> Vec<u8> {
+        self.0.output_bytes()
+    }
+    /// Get output split into lines (see `MemIo::output_lines`)
+    pub fn output_lines(&self) -> Vec<String> {
+        self.0.output_lines()
+    }
+    /// Get and clear the output buffer (see `MemIo::take_output`)
+    pub fn take_output(&self) -> Vec<u8> {
+        self.0.take_output()
+    }
+    /// Remove bytes from the output buffer (see `MemIo::consume_output`)
+    pub fn consume_output(&self, bytes: usize) {
+        self.0.consume_output(bytes)
+    }
+    /// Wait until output satisfies the predicate
+    /// (see `MemIo::wait_for_output`)
+    pub fn wait_for_output<F>(&self, predicate: F, timeout: Duration) -> bool
+        where F: FnMut(&[u8]) -> bool
+    {
+        self.0.wait_for_output(predicate, timeout)
+    }
+}
+
+impl MemWriter {
+    /// Push some bytes to an input buffer (see `MemIo::push_bytes`)
+    pub fn push_bytes<T:AsRef<[u8]>>(&mut self, val: T) {
+        self.0.push_bytes(val)
+    }
+    /// Push a sequence of chunks to the input buffer
+    /// (see `MemIo::push_chunks`)
+    pub fn push_chunks<I>(&mut self, chunks: I)
+        where I: IntoIterator, I::Item: AsRef<[u8]>
+    {
+        self.0.push_chunks(chunks)
+    }
+    /// Marks input as closed (see `MemIo::shutdown_input`)
+    pub fn shutdown_input(&self) {
+        self.0.shutdown_input()
+    }
+}
+
+impl Read for AppSide {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for AppSide {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl SocketError for AppSide {
+    fn take_socket_error(&self) -> io::Result<()> {
+        self.0.take_socket_error()
+    }
+}
+
+impl mio::Evented for AppSide {
+    fn register(&self, selector: &mut mio::Selector,
+        token: mio::Token, interest: mio::EventSet, opts: mio::PollOpt)
+        -> io::Result<()>
+    { self.0.register(selector, token, interest, opts) }
+    fn reregister(&self, selector: &mut mio::Selector, token: mio::Token,
+        interest: mio::EventSet, opts: mio::PollOpt) -> io::Result<()>
+    { self.0.reregister(selector, token, interest, opts) }
+    fn deregister(&self, selector: &mut mio::Selector) -> io::Result<()>
+    { self.0.deregister(selector) }
+}
+
+impl TestSide {
+    /// Push some bytes to an input buffer (see `MemIo::push_bytes`)
+    pub fn push_bytes<T:AsRef<[u8]>>(&mut self, val: T) {
+        self.0.push_bytes(val)
+    }
+    /// Push a sequence of chunks to the input buffer
+    /// (see `MemIo::push_chunks`)
+    pub fn push_chunks<I>(&mut self, chunks: I)
+        where I: IntoIterator, I::Item: AsRef<[u8]>
+    {
+        self.0.push_chunks(chunks)
+    }
+    /// Marks input as closed (see `MemIo::shutdown_input`)
+    pub fn shutdown_input(&self) {
+        self.0.shutdown_input()
+    }
+    /// Marks output as closed (see `MemIo::shutdown_output`)
+    pub fn shutdown_output(&self) {
+        self.0.shutdown_output()
+    }
+    /// Simulate connection reset by peer (see `MemIo::reset_connection`)
+    pub fn reset_connection(&self) {
+        self.0.reset_connection()
+    }
+    /// Get output as a string (see `MemIo::output_str`)
+    pub fn output_str(&self) -> String {
+        self.0.output_str()
+    }
+    /// Get data in the output buffer (see `MemIo::output_bytes`)
+    pub fn output_bytes(&self) -> Vec<u8> {
+        self.0.output_bytes()
+    }
+    /// Get output split into lines (see `MemIo::output_lines`)
+    pub fn output_lines(&self) -> Vec<String> {
+        self.0.output_lines()
+    }
+    /// Get and clear the output buffer (see `MemIo::take_output`)
+    pub fn take_output(&self) -> Vec<u8> {
+        self.0.take_output()
+    }
+    /// Remove bytes from the output buffer (see `MemIo::consume_output`)
+    pub fn consume_output(&self, bytes: usize) {
+        self.0.consume_output(bytes)
+    }
+    /// Wait until output satisfies the predicate
+    /// (see `MemIo::wait_for_output`)
+    pub fn wait_for_output<F>(&self, predicate: F, timeout: Duration) -> bool
+        where F: FnMut(&[u8]) -> bool
+    {
+        self.0.wait_for_output(predicate, timeout)
+    }
+    /// Get operations done by the application (see `MemIo::timeline`)
+    pub fn timeline(&self) -> Vec<IoEvent> {
+        self.0.timeline()
+    }
+}
+
+#[cfg(test)]
+mod self_test {
+    use std::thread;
+    use std::io::{Read, Write};
+    use stream::MemIo;
+
+    #[test]
+    fn split() {
+        let mut s = MemIo::new();
+        let (reader, mut writer) = s.split();
+        thread::spawn(move || {
+            writer.push_bytes("hello");
+            writer.shutdown_input();
+        }).join().unwrap();
+        let mut data = String::new();
+        s.read_to_string(&mut data).unwrap();
+        assert_eq!(s.write(data.as_bytes()).unwrap(), data.len());
+        assert_eq!(reader.take_output(), b"hello");
+    }
+
+    #[test]
+    fn endpoints() {
+        let (mut app, mut test) = MemIo::endpoints();
+        test.push_bytes("ping");
+        let mut buf = [0u8; 16];
+        assert_eq!(app.read(&mut buf).unwrap(), 4);
+        assert_eq!(app.write(b"pong").unwrap(), 4);
+        assert_eq!(test.output_str(), "pong");
+    }
+}
@@ -1,16 +1,35 @@
 use std::io;
+use std::str;
 use std::fmt;
+use std::mem;
+use std::thread;
+use std::fs::File;
+use std::path::Path;
+use std::net::{SocketAddr, Shutdown};
+use std::time::{Duration, Instant};
+use std::io::{Read, Write};
 use std::cmp::min;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::str::Utf8Error;
+use std::collections::VecDeque;
+use std::sync::{Arc, Weak, Mutex, MutexGuard, Condvar};
 
-use rotor::mio;
-use rotor_stream::SocketError;
+use rotor::{mio, Time};
+use rotor_stream::{SocketError, ActiveStream, Buf};
+
+use pcap::tcp_segments;
+use seq::next_seq;
+use scope::Registration;
 
 /// In memory stream
 ///
-/// The struct pretends to be `mio::Evented` but it have `unimplemented` panic
-/// when actually added to the loop. I.e. it should be used in tests which
-/// use plain state machine, and not the event loop.
+/// The struct implements `mio::Evented` by recording the registration (see
+/// `registration()`), so the application may register the stream itself,
+/// either through `MockLoop` or a real loop. But it never gets any events
+/// from the loop, i.e. it should be used in tests which call actions of
+/// plain state machine, and not the ones running the event loop. Like with
+/// epoll, registering the stream twice fails with `AlreadyExists`, and
+/// reregistering or deregistering a stream which is not registered fails
+/// with `NotFound`.
 ///
 /// Clarification: it implements `Read`/`Write` but, it's not a pipe. I.e.
 /// buffers for `Read` and `Write` are separate. You use `push_xxx` methods to
@@ -18,13 +37,221 @@ use rotor_stream::SocketError;
 ///
 /// You should clone the stream. Feed one to the application and second one
 /// to the unit testing code.
+///
+/// If you need an actual pipe between two state machines use `MemIo::pair()`.
+#[derive(Clone)]
+pub struct MemIo(Arc<Shared>);
+
+struct Shared {
+    bufs: Mutex<Bufs>,
+    // Signalled on each write, see `wait_for_output`
+    written: Condvar,
+}
+
+/// A single step of the I/O script
+///
+/// See `MemIo::scripted()` for more info.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IoStep {
+    /// Next `read` returns these bytes
+    ///
+    /// If the buffer passed to `read` is smaller than the data, the rest is
+    /// returned by the subsequent reads
+    Read(Vec<u8>),
+    /// Next `write` accepts at most this number of bytes
+    Write(usize),
+    /// Next `read` or `write` returns `WouldBlock`
+    WouldBlock,
+    /// Next `read` or `write` returns an error of this kind
+    Error(io::ErrorKind),
+    /// Next `read` returns end of stream
+    Eof,
+}
+
+/// Statistics of I/O operations done on the stream
+///
+/// Returned by `MemIo::stats()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IoStats {
+    /// Number of `read` calls
+    pub reads: usize,
+    /// Number of `write` calls
+    pub writes: usize,
+    /// Total number of bytes returned by `read`
+    pub bytes_read: u64,
+    /// Total number of bytes accepted by `write`
+    pub bytes_written: u64,
+    /// Number of `WouldBlock` errors returned by either operation
+    pub would_blocks: usize,
+    /// Number of other errors returned by either operation
+    pub errors: usize,
+}
+
+/// An I/O operation recorded in the stream's timeline
+///
+/// See `MemIo::timeline()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IoEvent {
+    /// Successful read of that many bytes (zero means end of stream)
+    Read(usize),
+    /// Read returned an error of this kind (including `WouldBlock`)
+    ReadError(io::ErrorKind),
+    /// Successful write of these bytes
+    Write(Vec<u8>),
+    /// Write returned an error of this kind (including `WouldBlock`)
+    WriteError(io::ErrorKind),
+    /// Input was closed by `shutdown_input()`
+    ShutdownInput,
+    /// Output was closed by `shutdown_output()`
+    ShutdownOutput,
+    /// Connection was reset by `reset_connection()`
+    Reset,
+    /// Output was flushed (including failed flushes)
+    Flush,
+}
+
+/// Socket options set by the application
+///
+/// Returned by `MemIo::socket_options()`. The value is `None` if the option
+/// was never set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SocketOptions {
+    /// Value passed to `set_nodelay`
+    pub nodelay: Option<bool>,
+    /// Value passed to `set_keepalive` (in seconds)
+    pub keepalive: Option<Option<u32>>,
+    /// Value passed to `set_linger`
+    pub linger: Option<Option<Duration>>,
+}
+
+/// A snapshot of the stream buffers
+///
+/// Created by `MemIo::checkpoint()`, see `MemIo::rollback()`.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    input: Vec<u8>,
+    input_chunks: VecDeque<usize>,
+    input_closed: bool,
+    delayed_input: VecDeque<(Time, Vec<u8>)>,
+    generated: VecDeque<Generated>,
+    output: Vec<u8>,
+    output_consumed: u64,
+    output_closed: bool,
+    reset: bool,
+    // Split points and the read failure are relative to this offset
+    bytes_read: u64,
+    split_points: VecDeque<u64>,
+    read_failure: Option<(usize, io::ErrorKind, String)>,
+}
+
+/// A position in the output of the stream
+///
+/// Created by `MemIo::mark()`, see `MemIo::output_since()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputMark(u64);
+
+enum Watermark {
+    Size(usize),
+    Delimiter(Vec<u8>),
+}
+
+type OutputCallback = Box<dyn FnMut(&[u8]) + Send>;
+
+type Observer = Arc<Mutex<Box<dyn FnMut(&IoEvent) + Send>>>;
+
+// Shared, so that checkpoints can keep generated input
+type Generator = Arc<Mutex<Box<dyn FnMut(u64, &mut [u8]) + Send>>>;
+
+// Input produced lazily (see `push_generated`)
 #[derive(Clone)]
-pub struct MemIo(Arc<Mutex<Bufs>>);
+struct Generated {
+    // Bytes of the input buffer which are read before this data
+    before: usize,
+    offset: u64,
+    len: u64,
+    fill: Generator,
+}
+
+impl fmt::Debug for Generated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Generated")
+            .field("before", &self.before)
+            .field("offset", &self.offset)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl Generated {
+    fn fill(&self, buf: &mut [u8]) {
+        let mut fill = self.fill.lock()
+            .expect("Poisoned generator of MemIo input");
+        (*fill)(self.offset, buf)
+    }
+}
 
 struct Bufs {
     input: Vec<u8>,
+    // Sizes of the chunks at the start of the input which must be returned
+    // by separate reads (see `push_chunks`)
+    input_chunks: VecDeque<usize>,
     input_closed: bool,
+    // Input which becomes available at the specified time, sorted by time
+    delayed_input: VecDeque<(Time, Vec<u8>)>,
+    now: Time,
+    read_chunk: usize,
+    random_chunking: Option<u64>,
     output: Vec<u8>,
+    output_closed: bool,
+    reset: bool,
+    write_chunk: usize,
+    output_capacity: usize,
+    read_errors: VecDeque<io::Error>,
+    write_errors: VecDeque<io::Error>,
+    // Number of the next reads and writes which return `WouldBlock`
+    would_block: usize,
+    flush_errors: VecDeque<io::Error>,
+    flush_failure: Option<(io::ErrorKind, String)>,
+    peer: Option<Weak<Shared>>,
+    script: VecDeque<IoStep>,
+    stats: IoStats,
+    readv_counts: Vec<usize>,
+    writev_counts: Vec<usize>,
+    timeline: Vec<(u64, Time, IoEvent)>,
+    watermarks: Vec<(Watermark, OutputCallback)>,
+    strict: bool,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    options: SocketOptions,
+    interrupt_every: usize,
+    read_failure: Option<(usize, io::Error)>,
+    input_limit: usize,
+    // Number of bytes written before each flush
+    flushes: Vec<u64>,
+    write_deadline: Option<Time>,
+    write_latency: Duration,
+    // Output which becomes visible at the specified time
+    pending_output: VecDeque<(Time, Vec<u8>)>,
+    // Visible output which is not yet passed to the peer (see `pair()`)
+    to_peer: Vec<u8>,
+    // Directions shut down by the application itself (see `shutdown()`)
+    shut_read: bool,
+    shut_write: bool,
+    registration: Registration,
+    observer: Option<Observer>,
+    // Events not yet passed to the observer
+    observed: Vec<IoEvent>,
+    // Number of bytes removed from the start of the output buffer
+    output_consumed: u64,
+    // Offset in the output up to which the test has seen the data
+    output_checked: u64,
+    generated: VecDeque<Generated>,
+    line_buffered: bool,
+    // Offsets in the input at which reads must break, sorted
+    split_points: VecDeque<u64>,
+    output_limit: usize,
+    // Bytes to show from the start and from the end in `Debug`
+    debug_limit: Option<(usize, usize)>,
 }
 
 impl MemIo {
@@ -32,22 +259,662 @@ impl MemIo {
     ///
     /// Stream start empty
     pub fn new() -> MemIo {
-        MemIo(Arc::new(Mutex::new(Bufs {
+        MemIo(Arc::new(Shared { bufs: Mutex::new(Bufs {
             input: Vec::new(),
+            input_chunks: VecDeque::new(),
             input_closed: false,
+            delayed_input: VecDeque::new(),
+            now: Time::zero(),
+            read_chunk: usize::MAX,
+            random_chunking: None,
             output: Vec::new(),
-        })))
+            output_closed: false,
+            reset: false,
+            write_chunk: usize::MAX,
+            output_capacity: usize::MAX,
+            read_errors: VecDeque::new(),
+            write_errors: VecDeque::new(),
+            would_block: 0,
+            flush_errors: VecDeque::new(),
+            flush_failure: None,
+            peer: None,
+            script: VecDeque::new(),
+            stats: IoStats::default(),
+            readv_counts: Vec::new(),
+            writev_counts: Vec::new(),
+            timeline: Vec::new(),
+            watermarks: Vec::new(),
+            strict: false,
+            peer_addr: None,
+            local_addr: None,
+            options: SocketOptions::default(),
+            interrupt_every: 0,
+            read_failure: None,
+            input_limit: usize::MAX,
+            flushes: Vec::new(),
+            write_deadline: None,
+            write_latency: Duration::new(0, 0),
+            pending_output: VecDeque::new(),
+            to_peer: Vec::new(),
+            shut_read: false,
+            shut_write: false,
+            registration: Registration::default(),
+            observer: None,
+            observed: Vec::new(),
+            output_consumed: 0,
+            output_checked: 0,
+            generated: VecDeque::new(),
+            line_buffered: false,
+            split_points: VecDeque::new(),
+            output_limit: usize::MAX,
+            debug_limit: None,
+        }), written: Condvar::new() }))
+    }
+    /// Create a stream which follows the script
+    ///
+    /// Each `read` and `write` call takes next step of the script. The
+    /// stream panics if the step doesn't match the operation (i.e. the
+    /// application writes when script expects a read). When the script is
+    /// exhausted, stream works like a normal `MemIo`.
+    pub fn scripted(steps: Vec<IoStep>) -> MemIo {
+        let io = MemIo::new();
+        io.bufs().script.extend(steps);
+        io
+    }
+    /// Create a stream which returns input byte by byte
+    ///
+    /// This is a shortcut for `set_read_chunk_limit(1)`. It's the simplest
+    /// way to find bugs in incremental parsers.
+    pub fn trickle() -> MemIo {
+        let io = MemIo::new();
+        io.set_read_chunk_limit(1);
+        io
+    }
+    /// Create two connected streams
+    ///
+    /// Everything written to one stream becomes input of the other one,
+    /// like in a `socketpair`. The written data is also kept in the output
+    /// buffer of the writer, so `output_str()` and friends work as usual.
+    /// Data written after the peer has shut down its input or reset the
+    /// connection is discarded, like in TCP.
+    pub fn pair() -> (MemIo, MemIo) {
+        let a = MemIo::new();
+        let b = MemIo::new();
+        a.bufs().peer = Some(Arc::downgrade(&b.0));
+        b.bufs().peer = Some(Arc::downgrade(&a.0));
+        (a, b)
+    }
+    /// Create a stream where everything written is available for reading
+    ///
+    /// This is a pipe looped to itself, useful for echo-style machines.
+    /// As with `pair()` the written data is also kept in the output buffer.
+    /// Shutting down the output closes the input too.
+    pub fn loopback() -> MemIo {
+        let io = MemIo::new();
+        io.bufs().peer = Some(Arc::downgrade(&io.0));
+        io
+    }
+    /// Create a stream with preallocated buffers
+    ///
+    /// Useful for benchmarks, so that reallocation of the buffers doesn't
+    /// dominate the results.
+    pub fn with_capacity(input: usize, output: usize) -> MemIo {
+        let io = MemIo::new();
+        io.reserve_input(input);
+        io.reserve_output(output);
+        io
+    }
+    /// Reserve space for at least `bytes` more bytes of input
+    pub fn reserve_input(&self, bytes: usize) {
+        self.bufs().input.reserve(bytes);
+    }
+    /// Reserve space for at least `bytes` more bytes of output
+    pub fn reserve_output(&self, bytes: usize) {
+        self.bufs().output.reserve(bytes);
     }
     /// Push some bytes to an input buffer of an application
     pub fn push_bytes<T:AsRef<[u8]>>(&mut self, val: T) {
         let mut bufs = self.bufs();
+        bufs.check_input_limit(val.as_ref().len() as u64);
         bufs.input.extend(val.as_ref());
         assert!(!bufs.input_closed);
+        bufs.signal_readable();
+    }
+    /// Push contents of the buffer to the input (see `push_bytes`)
+    pub fn push_buf(&mut self, buf: &Buf) {
+        self.push_bytes(&buf[..])
+    }
+    /// Push bytes which become readable only after `delay`
+    ///
+    /// The data is available when time of the stream (see `set_time`)
+    /// advances by `delay` from the current one. Data pushed with
+    /// different delays is read in the order of arrival time.
+    pub fn push_bytes_after<T:AsRef<[u8]>>(&mut self, delay: Duration, val: T)
+    {
+        let mut bufs = self.bufs();
+        assert!(!bufs.input_closed);
+        let time = bufs.now() + delay;
+        bufs.delay_input(time, val.as_ref().to_vec());
+        bufs.release_input();
+    }
+    /// Push `len` bytes of input which are generated lazily
+    ///
+    /// The `fill` function is called with offset from the start of this
+    /// data and the buffer to fill on each read (and peek) reaching this
+    /// data, so it must produce the same bytes for the same offset. This
+    /// allows to stream gigabytes into the application without allocating
+    /// them. The generated data is read in order with bytes pushed before
+    /// and after it, and is never joined with them in a single read.
+    pub fn push_generated<F>(&mut self, len: u64, fill: F)
+        where F: FnMut(u64, &mut [u8]) + Send + 'static
+    {
+        let mut bufs = self.bufs();
+        assert!(!bufs.input_closed);
+        if len == 0 {
+            return;
+        }
+        bufs.check_input_limit(len);
+        let before = bufs.input.len();
+        bufs.generated.push_back(Generated {
+            before,
+            offset: 0,
+            len,
+            fill: Arc::new(Mutex::new(Box::new(fill))),
+        });
+        bufs.signal_readable();
+    }
+    /// Push a sequence of chunks to the input buffer
+    ///
+    /// Unlike with `push_bytes` each chunk is guaranteed to be returned by
+    /// a separate `Read::read` (the chunk may still be split into several
+    /// reads by the chunk limits, but never joined with the next one).
+    pub fn push_chunks<I>(&mut self, chunks: I)
+        where I: IntoIterator, I::Item: AsRef<[u8]>
+    {
+        let mut bufs = self.bufs();
+        assert!(!bufs.input_closed);
+        for chunk in chunks {
+            bufs.push_chunk(chunk.as_ref());
+        }
+        bufs.signal_readable();
+    }
+    /// Push the contents of the file to the input buffer
+    ///
+    /// This is useful for large (binary) fixtures, for example traffic
+    /// captured from a real application.
+    pub fn push_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        self.push_bytes(data);
+        Ok(())
+    }
+    /// Push data sent by `source` from a pcap capture to the input buffer
+    ///
+    /// Payload of each TCP segment sent from `source` address is pushed as
+    /// a separate chunk (see `push_chunks`), so the application observes
+    /// the same read boundaries as the original one (given it was fast
+    /// enough to read each packet separately). There is no filtering by
+    /// destination, so the capture should contain a single connection from
+    /// that address.
+    pub fn push_pcap<P: AsRef<Path>>(&mut self, path: P, source: SocketAddr)
+        -> io::Result<()>
+    {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        let segments = tcp_segments(&data)?;
+        self.push_chunks(segments.into_iter()
+            .filter(|s| s.source == source)
+            .map(|s| s.payload));
+        Ok(())
+    }
+    /// Same as `push_pcap` but retains original timing of the packets
+    ///
+    /// The first segment is available for reading immediately, others
+    /// become available when time of the stream (see `set_time`) advances
+    /// according to the original inter-arrival time of the segments.
+    pub fn push_pcap_timed<P: AsRef<Path>>(&mut self, path: P,
+        source: SocketAddr)
+        -> io::Result<()>
+    {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        let segments = tcp_segments(&data)?;
+        let mut bufs = self.bufs();
+        assert!(!bufs.input_closed);
+        let now = bufs.now();
+        let mut start = None;
+        for seg in segments.into_iter().filter(|s| s.source == source) {
+            let start = *start.get_or_insert(seg.time);
+            // Timestamps in capture are not always monotonic
+            let delta = if seg.time > start {
+                seg.time - start
+            } else {
+                Duration::new(0, 0)
+            };
+            bufs.delay_input(now + delta, seg.payload);
+        }
+        bufs.release_input();
+        Ok(())
+    }
+    /// Set current time of the stream
+    ///
+    /// Input scheduled to arrive at or before this time becomes available
+    /// for reading. Time can't go backwards. While the stream is registered
+    /// through `MockLoop` the time of the loop is used instead, and it's
+    /// advanced by the loop (see `MockLoop::set_time`).
+    pub fn set_time(&self, now: Time) {
+        let mut bufs = self.bufs();
+        assert!(now >= bufs.now, "time can't go backwards");
+        bufs.now = now;
+        self.time_changed(bufs);
+    }
+    // Releases delayed input and output which are due
+    fn time_changed(&self, mut bufs: MutexGuard<Bufs>) {
+        bufs.release_input();
+        bufs.release_output(false);
+        self.output_changed(bufs);
+    }
+    /// Delay visibility of written data
+    ///
+    /// Data written by the application becomes visible in the output buffer
+    /// (and for the peer of `MemIo::pair()`) only when time of the stream
+    /// (see `set_time`) advances by `latency`, or when `tick()` is called.
+    /// Delayed data counts against `set_output_capacity()` limit.
+    pub fn set_write_latency(&self, latency: Duration) {
+        self.bufs().write_latency = latency;
+    }
+    /// Make all delayed output visible (see `set_write_latency`)
+    pub fn tick(&self) {
+        let mut bufs = self.bufs();
+        bufs.release_output(true);
+        self.output_changed(bufs);
+    }
+    /// Enable strict mode
+    ///
+    /// In strict mode, the stream panics when the last handle is dropped
+    /// and there is some input that was not read by the application
+    /// (including delayed input and remaining script) or some output that
+    /// was not checked by the test. Any method which returns the output
+    /// (`output_str`, `output_bytes`, `with_output`, `take_output` and so
+    /// on) marks all of the current output as checked, `consume_output`
+    /// removes the output from the check. This helps to find tests which
+    /// stopped driving the state machine too early.
+    pub fn set_strict(&self, strict: bool) {
+        self.bufs().strict = strict;
+    }
+    /// Set address returned by `peer_addr()`
+    pub fn set_peer_addr(&self, addr: SocketAddr) {
+        self.bufs().peer_addr = Some(addr);
+    }
+    /// Set address returned by `local_addr()`
+    pub fn set_local_addr(&self, addr: SocketAddr) {
+        self.bufs().local_addr = Some(addr);
+    }
+    /// Returns the address of the remote peer (like `TcpStream::peer_addr`)
+    ///
+    /// Returns `NotConnected` error unless set by `set_peer_addr()`
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.bufs().peer_addr.ok_or_else(|| io::Error::new(
+            io::ErrorKind::NotConnected, "peer address of mock stream is \
+                                         not set"))
+    }
+    /// Returns the local address (like `TcpStream::local_addr`)
+    ///
+    /// Returns `NotConnected` error unless set by `set_local_addr()`
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.bufs().local_addr.ok_or_else(|| io::Error::new(
+            io::ErrorKind::NotConnected, "local address of mock stream is \
+                                         not set"))
+    }
+    /// Record `TCP_NODELAY` option (like `TcpStream::set_nodelay`)
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.bufs().options.nodelay = Some(nodelay);
+        Ok(())
+    }
+    /// Record keepalive option (like `TcpStream::set_keepalive`)
+    pub fn set_keepalive(&self, seconds: Option<u32>) -> io::Result<()> {
+        self.bufs().options.keepalive = Some(seconds);
+        Ok(())
+    }
+    /// Record `SO_LINGER` option
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        self.bufs().options.linger = Some(linger);
+        Ok(())
+    }
+    /// Returns socket options set by the application so far
+    pub fn socket_options(&self) -> SocketOptions {
+        self.bufs().options
+    }
+    /// Make every `n`-th read and every `n`-th write return `Interrupted`
+    ///
+    /// Reads and writes are counted separately, the call which is retried
+    /// after `Interrupted` error is counted too, so it normally succeeds.
+    /// Zero disables interruptions.
+    pub fn set_interrupt_every(&self, n: usize) {
+        self.bufs().interrupt_every = n;
+    }
+    /// Make reads fail after `bytes` more bytes are read
+    ///
+    /// Reads return exactly `bytes` bytes (given there is enough input)
+    /// and then the next read returns the error. Only a single error is
+    /// returned, use `reset_connection()` if you need the stream to be
+    /// broken after that.
+    pub fn fail_read_after(&mut self, bytes: usize, err: io::Error) {
+        self.bufs().read_failure = Some((bytes, err));
+    }
+    /// Limit the size of unread input
+    ///
+    /// Pushing more data than the limit while the application has not read
+    /// previous data yet panics. Generated input (see `push_generated`)
+    /// and data written by the peer of `MemIo::pair()` count too. This is
+    /// useful to check that the protocol reads (and validates) data in
+    /// time, e.g. to enforce limit on the request size. Use `usize::MAX` to
+    /// remove the limit.
+    pub fn set_input_limit(&self, limit: usize) {
+        self.bufs().input_limit = limit;
+    }
+    /// Limit the size of the output buffer
+    ///
+    /// Writing more than the limit panics, showing the start and the end of
+    /// the output. This catches applications stuck in a write loop before
+    /// they eat all the memory. Unlike `set_output_capacity()` this is not
+    /// a condition the application is expected to handle. Use `usize::MAX`
+    /// to remove the limit.
+    pub fn set_output_limit(&self, limit: usize) {
+        self.bufs().output_limit = limit;
+    }
+    /// Limit the amount of data shown by the `Debug` implementation
+    ///
+    /// Buffers longer than `head + tail` bytes are shown as their first
+    /// `head` and last `tail` bytes with the number of skipped bytes in
+    /// between. This keeps failure messages readable when the stream holds
+    /// megabytes of data. By default whole buffers are shown.
+    pub fn set_debug_limit(&self, head: usize, tail: usize) {
+        self.bufs().debug_limit = Some((head, tail));
+    }
+    /// Make writes fail with `TimedOut` when deadline is reached
+    ///
+    /// The deadline is checked against the time of the stream (see
+    /// `set_time`). Use `None` to remove the deadline.
+    pub fn set_write_deadline(&self, deadline: Option<Time>) {
+        self.bufs().write_deadline = deadline;
+    }
+    /// Make one of the next `Read::read` calls return an error
+    ///
+    /// Errors are queued, each `read` returns the next error from the queue
+    /// without touching the input buffer. When the queue is empty reads work
+    /// as usual.
+    pub fn push_read_error(&mut self, err: io::Error) {
+        self.bufs().read_errors.push_back(err);
+    }
+    /// Make one of the next `Write::write` calls return an error
+    ///
+    /// Works the same way as `push_read_error` but for the output side.
+    pub fn push_write_error(&mut self, err: io::Error) {
+        self.bufs().write_errors.push_back(err);
+    }
+    /// Make the next `n` I/O calls return `WouldBlock`
+    ///
+    /// Reads and writes are counted together, e.g. with `n = 2` a read
+    /// followed by a write both fail and the next call works as usual.
+    /// This is useful to check that the application re-arms its interest
+    /// and resumes afterwards. Errors queued by `push_read_error` and
+    /// `push_write_error` are returned first, use them to affect only one
+    /// direction.
+    pub fn would_block_next(&mut self, n: usize) {
+        self.bufs().would_block = n;
+    }
+    /// Make the next `Write::flush` call return an error
+    ///
+    /// Errors are queued like in `push_write_error`.
+    pub fn fail_flush(&mut self, err: io::Error) {
+        self.bufs().flush_errors.push_back(err);
+    }
+    /// Make all subsequent `Write::flush` calls return an error
+    ///
+    /// The error is recreated from the kind and description of the
+    /// original for each call.
+    pub fn fail_flush_permanently(&mut self, err: io::Error) {
+        self.bufs().flush_failure = Some((err.kind(), err.to_string()));
+    }
+    /// Limit number of bytes returned by a single `Read::read` call
+    ///
+    /// By default all buffered input is returned at once (as long as it fits
+    /// the buffer passed to `read`). Setting small limit helps to find bugs
+    /// in parsers which don't expect data to arrive in fragments. Use
+    /// `usize::MAX` to remove the limit.
+    pub fn set_read_chunk_limit(&self, limit: usize) {
+        assert!(limit > 0, "zero-sized reads are indistinguishable from EOF");
+        self.bufs().read_chunk = limit;
+    }
+    /// Make reads break exactly at the specified offsets of the input
+    ///
+    /// Offsets are counted from the start of the stream (i.e. include bytes
+    /// already read). A single read never crosses any of the offsets, so
+    /// `set_split_points(&[5, 9, 10])` with enough input makes reads of
+    /// 5, 4, 1 bytes and then the rest (subject to other limits).
+    pub fn set_split_points(&self, offsets: &[u64]) {
+        let mut points = offsets.to_vec();
+        points.sort();
+        points.dedup();
+        self.bufs().split_points = points.into_iter().collect();
+    }
+    /// Deliver input line by line (like a terminal in canonical mode)
+    ///
+    /// Each read returns at most one line including the newline character.
+    /// Input without a newline at the end is not readable until the newline
+    /// is pushed or the input is closed.
+    pub fn set_line_buffered(&self, enable: bool) {
+        self.bufs().line_buffered = enable;
+    }
+    /// Make reads return fragments of random size
+    ///
+    /// Sizes are derived from the `seed` deterministically, so failing test
+    /// can be reproduced by using the same seed. Fragments are never larger
+    /// than the limit set by `set_read_chunk_limit()`.
+    pub fn set_random_chunking(&self, seed: u64) {
+        self.bufs().random_chunking = Some(seed);
+    }
+    /// Limit number of bytes accepted by a single `Write::write` call
+    ///
+    /// This simulates short writes, so application must handle the case
+    /// when only a part of the data is written. Use `usize::MAX` to remove
+    /// the limit.
+    pub fn set_write_limit(&self, limit: usize) {
+        assert!(limit > 0, "zero-sized writes are treated as errors");
+        self.bufs().write_chunk = limit;
+    }
+    /// Limit the size of the output buffer
+    ///
+    /// When the buffer is full `Write::write` returns `WouldBlock` until
+    /// some data is removed with `consume_output()`. This simulates a slow
+    /// peer which doesn't read data fast enough. Use `usize::MAX` to remove
+    /// the limit.
+    pub fn set_output_capacity(&self, capacity: usize) {
+        self.bufs().output_capacity = capacity;
+    }
+    /// Remove `bytes` from the start of the output buffer
+    ///
+    /// This is like peer has read the data, and frees the space in the
+    /// buffer limited by `set_output_capacity()`.
+    pub fn consume_output(&self, bytes: usize) {
+        let mut bufs = self.bufs();
+        assert!(bytes <= bufs.output.len(),
+            "trying to consume more than there is in the output buffer");
+        bufs.output.drain(..bytes);
+        bufs.output_consumed += bytes as u64;
     }
     /// Marks input as closed so application gets end-of-stream event on next
     /// read
     pub fn shutdown_input(&self) {
-        self.bufs().input_closed = true;
+        {
+            let mut bufs = self.bufs();
+            bufs.input_closed = true;
+            bufs.record(IoEvent::ShutdownInput);
+            bufs.signal_readable();
+        }
+        self.run_observer();
+    }
+    /// Marks output as closed so application gets `BrokenPipe` error on
+    /// the next write
+    ///
+    /// For streams created by `MemIo::pair()` this also closes input of
+    /// the peer.
+    pub fn shutdown_output(&self) {
+        let peer = {
+            let mut bufs = self.bufs();
+            bufs.output_closed = true;
+            bufs.record(IoEvent::ShutdownOutput);
+            bufs.peer.as_ref().and_then(|p| p.upgrade())
+        };
+        if let Some(peer) = peer {
+            let mut peer = peer.bufs.lock()
+                .expect("Poisoned MemIo (mock stream)");
+            peer.input_closed = true;
+            peer.signal_readable();
+        }
+        self.run_observer();
+    }
+    /// Shut down the stream by the application (like `TcpStream::shutdown`)
+    ///
+    /// This is the application-side counterpart of `shutdown_input` and
+    /// `shutdown_output`. Shutting down a direction which is already shut
+    /// down by this method is reported as a `NotConnected` error, so that
+    /// double shutdowns in the application don't go unnoticed.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let (read, write) = match how {
+            Shutdown::Read => (true, false),
+            Shutdown::Write => (false, true),
+            Shutdown::Both => (true, true),
+        };
+        let (read, write) = {
+            let mut bufs = self.bufs();
+            let read = read && !bufs.shut_read;
+            let write = write && !bufs.shut_write;
+            if !read && !write {
+                return Err(io::Error::new(io::ErrorKind::NotConnected,
+                    "mock stream is already shut down"));
+            }
+            bufs.shut_read |= read;
+            bufs.shut_write |= write;
+            (read, write)
+        };
+        if read {
+            self.shutdown_input();
+        }
+        if write {
+            self.shutdown_output();
+        }
+        Ok(())
+    }
+    /// Set a callback which is called on every operation of the stream
+    ///
+    /// The callback receives the same events which are recorded in the
+    /// `timeline()`. It's called after the operation is complete and
+    /// without the stream being locked, so it may inspect the stream. Only
+    /// one observer may be set, the new one replaces the previous.
+    pub fn set_observer<F>(&self, f: F)
+        where F: FnMut(&IoEvent) + Send + 'static
+    {
+        self.bufs().observer = Some(Arc::new(Mutex::new(Box::new(f))));
+    }
+    /// Number of live handles (clones) of this stream
+    ///
+    /// Includes handles created by `split()` and `endpoints()`, but not
+    /// the peer of `pair()`.
+    pub fn handle_count(&self) -> usize {
+        Arc::strong_count(&self.0)
+    }
+    /// Panics unless there are exactly `expected` live handles
+    ///
+    /// Useful after the state machine returned `Response::done()` to check
+    /// that it dropped the stream (i.e. `assert_handle_count(1)` when only
+    /// the test keeps a handle). A leaked stream means a connection that is
+    /// never closed in production.
+    pub fn assert_handle_count(&self, expected: usize) {
+        let count = self.handle_count();
+        if count != expected {
+            panic!("MemIo (mock stream) has {} live handles, expected {}. \
+                    Does the application leak the stream?", count, expected);
+        }
+    }
+    /// Returns token, interest and poll options the stream is registered
+    /// with in the loop, if any
+    pub fn registration(&self)
+        -> Option<(mio::Token, mio::EventSet, mio::PollOpt)>
+    {
+        self.bufs().registration.get()
+    }
+    /// Returns true if input is closed (by either side)
+    pub fn is_input_closed(&self) -> bool {
+        self.bufs().input_closed
+    }
+    /// Returns true if output is closed (by either side)
+    pub fn is_output_closed(&self) -> bool {
+        self.bufs().output_closed
+    }
+    /// Simulate connection reset by peer
+    ///
+    /// All subsequent reads and writes return `ConnectionReset` error. Input
+    /// which is not read by the application yet is discarded. For streams
+    /// created by `MemIo::pair()` the peer is reset too.
+    pub fn reset_connection(&self) {
+        let peer = {
+            let mut bufs = self.bufs();
+            bufs.reset_connection();
+            // the loopback stream is its own peer, it's already reset
+            bufs.peer.as_ref().and_then(|p| p.upgrade())
+                .filter(|p| !Arc::ptr_eq(p, &self.0))
+        };
+        self.run_observer();
+        if let Some(peer) = peer {
+            peer.bufs.lock().expect("Poisoned MemIo (mock stream)")
+                .reset_connection();
+            MemIo(peer).run_observer();
+        }
+    }
+    /// Return the stream to the initial state to reuse it for another test
+    ///
+    /// Clears input and output buffers (including delayed and generated
+    /// data), reopens closed input and output, drops queued and permanent
+    /// errors, split points, the write deadline and script, and zeroes
+    /// statistics, timeline and flush counters.
+    ///
+    /// Kept are: time of the stream, chunk, capacity, input and output
+    /// limits, random chunking, line buffering, interruptions, write
+    /// latency, strict mode, debug limits, addresses, socket options, the
+    /// observer, output callbacks, registration and the peer.
+    pub fn reset(&self) {
+        let mut bufs = self.bufs();
+        bufs.input.clear();
+        bufs.input_chunks.clear();
+        bufs.generated.clear();
+        bufs.input_closed = false;
+        bufs.delayed_input.clear();
+        bufs.output.clear();
+        bufs.output_consumed = 0;
+        bufs.output_checked = 0;
+        bufs.pending_output.clear();
+        bufs.to_peer.clear();
+        bufs.output_closed = false;
+        bufs.reset = false;
+        bufs.shut_read = false;
+        bufs.shut_write = false;
+        bufs.read_errors.clear();
+        bufs.write_errors.clear();
+        bufs.would_block = 0;
+        bufs.flush_errors.clear();
+        bufs.flush_failure = None;
+        bufs.read_failure = None;
+        bufs.split_points.clear();
+        bufs.write_deadline = None;
+        bufs.script.clear();
+        bufs.stats = IoStats::default();
+        bufs.readv_counts.clear();
+        bufs.writev_counts.clear();
+        bufs.timeline.clear();
+        bufs.observed.clear();
+        bufs.flushes.clear();
     }
     /// Get output as a string
     ///
@@ -60,7 +927,69 @@ impl MemIo {
     pub fn output_str(&self) -> String {
         // Unfortunately we can't return a slice, because of borrowing rules
         // but it's for unit tests, so we don't care performance
-        String::from_utf8_lossy(&self.bufs().output).to_string()
+        let mut bufs = self.bufs();
+        bufs.mark_output_checked();
+        String::from_utf8_lossy(&bufs.output).to_string()
+    }
+    /// Get a copy of the output as a `netbuf::Buf`
+    ///
+    /// This is the buffer type used by `rotor_stream`, so the output may be
+    /// checked by the same code that parses it in the application.
+    pub fn output_as_buf(&self) -> Buf {
+        let mut bufs = self.bufs();
+        bufs.mark_output_checked();
+        let mut buf = Buf::new();
+        buf.extend(&bufs.output);
+        buf
+    }
+    /// Get output as a string, failing if it's not valid UTF-8
+    ///
+    /// Use this for text protocols, so that invalid data emitted by the
+    /// application isn't masked by replacement characters.
+    pub fn output_str_strict(&self) -> Result<String, Utf8Error> {
+        let mut bufs = self.bufs();
+        bufs.mark_output_checked();
+        str::from_utf8(&bufs.output).map(|x| x.to_string())
+    }
+    /// Get output split into lines
+    ///
+    /// Both `\n` and `\r\n` are recognized as line endings, line endings
+    /// are not included in the result. The last line is returned even if
+    /// it's not terminated yet. Data is converted like in `output_str()`.
+    pub fn output_lines(&self) -> Vec<String> {
+        self.output_str().lines().map(|x| x.to_string()).collect()
+    }
+    /// Get output split into frames by a delimiter
+    ///
+    /// Delimiters are not included in the frames. The last frame is
+    /// returned even if it's not terminated by the delimiter yet (but not
+    /// when it's empty).
+    pub fn output_frames<T: AsRef<[u8]>>(&self, delimiter: T) -> Vec<Vec<u8>>
+    {
+        let delimiter = delimiter.as_ref();
+        assert!(!delimiter.is_empty());
+        self.with_output(|mut data| {
+            let mut frames = Vec::new();
+            while let Some(pos) = data.windows(delimiter.len())
+                                      .position(|w| w == delimiter)
+            {
+                frames.push(data[..pos].to_vec());
+                data = &data[pos + delimiter.len()..];
+            }
+            if !data.is_empty() {
+                frames.push(data.to_vec());
+            }
+            frames
+        })
+    }
+    /// Get output split into frames of fixed size
+    ///
+    /// The last frame may be shorter if there is not enough data.
+    pub fn output_fixed_frames(&self, size: usize) -> Vec<Vec<u8>> {
+        assert!(size > 0);
+        self.with_output(|data| {
+            data.chunks(size).map(|x| x.to_vec()).collect()
+        })
     }
     /// Get data in the output buffer
     ///
@@ -69,76 +998,958 @@ impl MemIo {
     pub fn output_bytes(&self) -> Vec<u8> {
         // Unfortunately we can't return a slice, because of borrowing rules
         // but it's for unit tests, so we don't care performance
-        self.bufs().output.clone()
+        let mut bufs = self.bufs();
+        bufs.mark_output_checked();
+        bufs.output.clone()
     }
-    fn bufs(&self) -> MutexGuard<Bufs> {
-        self.0.lock().expect("Poisoned MemIo (mock stream)")
+    /// Call a function when output buffer reaches `size` bytes
+    ///
+    /// The function is called once, right after the write which made the
+    /// buffer large enough (or immediately on next write if it's already
+    /// large enough). The function receives the whole output buffer.
+    pub fn on_output_size<F>(&self, size: usize, f: F)
+        where F: FnMut(&[u8]) + Send + 'static
+    {
+        self.bufs().watermarks.push((Watermark::Size(size), Box::new(f)));
     }
-}
-
-impl SocketError for MemIo {
-    fn take_socket_error(&self) -> io::Result<()> {
-        Ok(())
+    /// Call a function when output buffer contains a `delimiter`
+    ///
+    /// Works the same way as `on_output_size`.
+    pub fn on_output_delimiter<T, F>(&self, delimiter: T, f: F)
+        where T: AsRef<[u8]>, F: FnMut(&[u8]) + Send + 'static
+    {
+        let delimiter = delimiter.as_ref().to_vec();
+        assert!(!delimiter.is_empty());
+        self.bufs().watermarks.push((Watermark::Delimiter(delimiter),
+                                     Box::new(f)));
     }
-}
-
-impl fmt::Debug for MemIo {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    /// Run a function with a reference to the output buffer
+    ///
+    /// This is like `output_bytes()` but doesn't copy the data. The stream
+    /// is locked while the function is running, so don't use the stream
+    /// (or it's clones) inside the closure, it will deadlock.
+    pub fn with_output<F, R>(&self, f: F) -> R
+        where F: FnOnce(&[u8]) -> R
+    {
+        let mut bufs = self.bufs();
+        bufs.mark_output_checked();
+        f(&bufs.output)
+    }
+    /// Write contents of the output buffer to a file
+    ///
+    /// The file is overwritten if it exists. The data in the buffer are not
+    /// discarded.
+    pub fn write_output_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()>
+    {
+        let mut file = File::create(path)?;
+        self.with_output(|data| file.write_all(data))
+    }
+    /// Wait until output satisfies the predicate
+    ///
+    /// This is useful if the application runs in another thread. The
+    /// predicate is checked right away and after each write. Returns `false`
+    /// if predicate is not satisfied within `timeout`.
+    pub fn wait_for_output<F>(&self, mut predicate: F, timeout: Duration)
+        -> bool
+        where F: FnMut(&[u8]) -> bool
+    {
+        let deadline = Instant::now() + timeout;
+        let mut bufs = self.bufs();
+        loop {
+            if predicate(&bufs.output) {
+                bufs.mark_output_checked();
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            bufs = self.0.written.wait_timeout(bufs, deadline - now)
+                .expect("Poisoned MemIo (mock stream)").0;
+        }
+    }
+    /// Get data in the output buffer and clear the buffer
+    ///
+    /// This is useful for tests consisting of multiple request-response
+    /// phases, where only the output of the last phase is interesting.
+    pub fn take_output(&self) -> Vec<u8> {
+        let mut bufs = self.bufs();
+        bufs.output_consumed += bufs.output.len() as u64;
+        bufs.mark_output_checked();
+        mem::take(&mut bufs.output)
+    }
+    /// Remember current end of the output
+    ///
+    /// Use `output_since()` to get data written after this point, while
+    /// the full output is still kept in the buffer.
+    pub fn mark(&self) -> OutputMark {
         let bufs = self.bufs();
-        fmt.debug_struct("MemIo")
-        .field("input", &String::from_utf8_lossy(&bufs.input))
-        .field("input_closed", &bufs.input_closed)
-        .field("output", &String::from_utf8_lossy(&bufs.output))
-        .finish()
+        OutputMark(bufs.output_consumed + bufs.output.len() as u64)
     }
-}
-
-impl io::Read for MemIo {
-    fn read(&mut self, val: &mut [u8]) -> io::Result<usize> {
+    /// Get data written after the `mark` was created
+    ///
+    /// If some of that data is already removed from the buffer (by
+    /// `take_output()` or `consume_output()`) only the rest is returned.
+    pub fn output_since(&self, mark: OutputMark) -> Vec<u8> {
+        let mut bufs = self.bufs();
+        bufs.mark_output_checked();
+        let start = mark.0.saturating_sub(bufs.output_consumed) as usize;
+        bufs.output[min(start, bufs.output.len())..].to_vec()
+    }
+    /// Get statistics of I/O operations done on the stream so far
+    ///
+    /// This is useful to check that protocol doesn't do too many small
+    /// reads or writes.
+    pub fn stats(&self) -> IoStats {
+        self.bufs().stats
+    }
+    /// Make a snapshot of input and output buffers
+    ///
+    /// Includes closed/reset state of the stream, pending split points and
+    /// `fail_read_after` too. Other settings, statistics and the timeline
+    /// are not included.
+    pub fn checkpoint(&self) -> Checkpoint {
+        let bufs = self.bufs();
+        Checkpoint {
+            input: bufs.input.clone(),
+            input_chunks: bufs.input_chunks.clone(),
+            input_closed: bufs.input_closed,
+            delayed_input: bufs.delayed_input.clone(),
+            generated: bufs.generated.clone(),
+            output: bufs.output.clone(),
+            output_consumed: bufs.output_consumed,
+            output_closed: bufs.output_closed,
+            reset: bufs.reset,
+            bytes_read: bufs.stats.bytes_read,
+            split_points: bufs.split_points.iter().cloned()
+                .filter(|&x| x > bufs.stats.bytes_read).collect(),
+            read_failure: bufs.read_failure.as_ref()
+                .map(|&(left, ref e)| (left, e.kind(), e.to_string())),
+        }
+    }
+    /// Restore buffers to the state saved by `checkpoint()`
+    ///
+    /// Delayed input which is already due at the current time is released
+    /// immediately. The same checkpoint may be used multiple times. Note:
+    /// the state of the application should be restored too, usually by
+    /// keeping a clone of the state machine.
+    pub fn rollback(&self, cp: &Checkpoint) {
         let mut bufs = self.bufs();
-        let bytes = min(val.len(), bufs.input.len());
+        bufs.input = cp.input.clone();
+        bufs.input_chunks = cp.input_chunks.clone();
+        bufs.input_closed = cp.input_closed;
+        bufs.delayed_input = cp.delayed_input.clone();
+        bufs.generated = cp.generated.clone();
+        bufs.output = cp.output.clone();
+        bufs.output_consumed = cp.output_consumed;
+        bufs.output_closed = cp.output_closed;
+        bufs.reset = cp.reset;
+        // statistics are kept, so split points are moved to the same
+        // position relative to the restored input
+        let shift = bufs.stats.bytes_read - cp.bytes_read;
+        bufs.split_points = cp.split_points.iter()
+            .map(|&x| x + shift).collect();
+        bufs.read_failure = cp.read_failure.as_ref()
+            .map(|&(left, kind, ref msg)| {
+                (left, io::Error::new(kind, msg.clone()))
+            });
+        self.time_changed(bufs);
+    }
+    /// Number of `Write::flush` calls (including failed ones)
+    pub fn flush_count(&self) -> usize {
+        self.bufs().flushes.len()
+    }
+    /// Number of bytes written before each of the `Write::flush` calls
+    ///
+    /// Numbers are offsets from the start of the stream (they are not
+    /// affected by `take_output()`), so you can check that application
+    /// flushes at message boundaries.
+    pub fn flush_offsets(&self) -> Vec<u64> {
+        self.bufs().flushes.clone()
+    }
+    /// Get all I/O operations done on the stream in chronological order
+    ///
+    /// This allows to check interleaving of operations, e.g. that the
+    /// request is fully read before the response is written.
+    pub fn timeline(&self) -> Vec<IoEvent> {
+        self.bufs().timeline.iter().map(|(_, _, e)| e.clone()).collect()
+    }
+    /// Same as `timeline()` but each operation is stamped with the time
+    ///
+    /// When the stream is registered through `MockLoop` this is the time of
+    /// the mock loop, otherwise the time of the stream (see `set_time`).
+    pub fn timed_timeline(&self) -> Vec<(Time, IoEvent)> {
+        self.bufs().timeline.iter()
+            .map(|&(_, time, ref e)| (time, e.clone())).collect()
+    }
+    /// Same as `timeline()` but each operation is stamped with a number
+    ///
+    /// Numbers are taken from the sequence shared with operations of
+    /// `MockLoop` (see `MockLoop::sequenced_operations`), so the order of
+    /// I/O relative to registrations can be checked.
+    pub fn sequenced_timeline(&self) -> Vec<(u64, IoEvent)> {
+        self.bufs().timeline.iter()
+            .map(|&(seq, _, ref e)| (seq, e.clone())).collect()
+    }
+    /// Read input without consuming it
+    ///
+    /// Works like `TcpStream::peek`: returns the bytes the next read into
+    /// a buffer of the same size would return (chunk limits, split points
+    /// and line buffering apply), `Ok(0)` at the end of stream, or
+    /// `WouldBlock` if there is no data yet. Queued errors and the script
+    /// are not taken into account. Peeking is not recorded in statistics
+    /// and timeline.
+    pub fn peek(&self, val: &mut [u8]) -> io::Result<usize> {
+        let bufs = self.bufs();
+        if bufs.reset {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset,
+                "mocked connection is reset"));
+        }
+        if let Some(&(0, ref err)) = bufs.read_failure.as_ref() {
+            return Err(io::Error::new(err.kind(), err.to_string()));
+        }
+        if let Some(gen) = bufs.generated.front().filter(|g| g.before == 0) {
+            let max = min(val.len() as u64, gen.len - gen.offset) as usize;
+            let (bytes, _) = bufs.next_fragment(max);
+            gen.fill(&mut val[..bytes]);
+            return Ok(bytes);
+        }
+        let (bytes, _) = bufs.next_fragment(min(val.len(), bufs.available()));
         if bytes > 0 {
-            assert_eq!(io::copy(
-                &mut io::Cursor::new(&bufs.input),
-                &mut io::Cursor::new(val))
-                .expect("copy always work"), bytes as u64);
-            bufs.input.drain(..bytes);
+            val[..bytes].clone_from_slice(&bufs.input[..bytes]);
             Ok(bytes)
+        } else if bufs.input_closed {
+            Ok(0)
         } else {
-            if bufs.input_closed {
-                Ok(0)
-            } else {
-                Err(io::Error::new(io::ErrorKind::WouldBlock,
-                    "no data in mocked input buffer"))
-            }
+            Err(io::Error::new(io::ErrorKind::WouldBlock,
+                "no data in mocked input buffer"))
         }
     }
-}
-impl io::Write for MemIo {
-    fn write(&mut self, val: &[u8]) -> io::Result<usize> {
-        let mut bufs = self.bufs();
-        io::copy(&mut io::Cursor::new(val), &mut bufs.output)
-            .map(|x| x as usize)
+    /// Read into multiple buffers (like `readv` system call)
+    ///
+    /// The operation is done as a single `Read::read` call (i.e. it's
+    /// subject to the chunk limits and counted as one read). The number of
+    /// buffers is recorded, see `readv_buffer_counts()`.
+    pub fn readv(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        self.bufs().readv_counts.push(bufs.len());
+        let total = bufs.iter().map(|b| b.len()).sum();
+        let mut tmp = vec![0u8; total];
+        let bytes = self.read(&mut tmp)?;
+        let mut data = &tmp[..bytes];
+        for buf in bufs.iter_mut() {
+            let n = min(buf.len(), data.len());
+            buf[..n].clone_from_slice(&data[..n]);
+            data = &data[n..];
+        }
+        Ok(bytes)
+    }
+    /// Write from multiple buffers (like `writev` system call)
+    ///
+    /// The operation is done as a single `Write::write` call of
+    /// concatenated buffers. The number of buffers is recorded, see
+    /// `writev_buffer_counts()`.
+    pub fn writev(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        self.bufs().writev_counts.push(bufs.len());
+        let mut tmp = Vec::new();
+        for buf in bufs {
+            tmp.extend(*buf);
+        }
+        self.write(&tmp)
+    }
+    /// Number of buffers passed to each `readv()` call
+    pub fn readv_buffer_counts(&self) -> Vec<usize> {
+        self.bufs().readv_counts.clone()
+    }
+    /// Number of buffers passed to each `writev()` call
+    pub fn writev_buffer_counts(&self) -> Vec<usize> {
+        self.bufs().writev_counts.clone()
+    }
+    // Notifies waiters, runs watermark callbacks and passes data to the
+    // peer. All of this is done after the lock is released
+    fn output_changed(&self, mut bufs: MutexGuard<Bufs>) {
+        let fired = bufs.fired_watermarks();
+        let to_peer = mem::take(&mut bufs.to_peer);
+        let peer = bufs.peer.as_ref().and_then(|p| p.upgrade());
+        let output_closed = bufs.output_closed;
+        drop(bufs);
+        self.0.written.notify_all();
+        // Callbacks are run without lock, so they can use the stream
+        if let Some((output, callbacks)) = fired {
+            for mut cb in callbacks {
+                cb(&output);
+            }
+        }
+        // Peer is locked after our own lock is released, so two threads
+        // writing into both ends of the pair can't deadlock
+        if let Some(peer) = peer {
+            if !to_peer.is_empty() {
+                let mut peer = peer.bufs.lock()
+                    .expect("Poisoned MemIo (mock stream)");
+                // Data arriving after the peer has shut down or reset its
+                // input is discarded. But data which was in flight when our
+                // own output was shut down still arrives before the EOF.
+                if !peer.reset && (!peer.input_closed || output_closed) {
+                    peer.check_input_limit(to_peer.len() as u64);
+                    peer.input.extend(to_peer);
+                    peer.signal_readable();
+                }
+            }
+        }
+    }
+    fn run_observer(&self) {
+        let (observer, events) = {
+            let mut bufs = self.bufs();
+            match bufs.observer.clone() {
+                Some(observer) => {
+                    (observer, mem::take(&mut bufs.observed))
+                }
+                None => return,
+            }
+        };
+        let mut callback = observer.lock()
+            .expect("Poisoned MemIo observer");
+        for event in &events {
+            (*callback)(event);
+        }
+    }
+    fn bufs<'a>(&'a self) -> MutexGuard<'a, Bufs> {
+        self.0.bufs.lock().expect("Poisoned MemIo (mock stream)")
+    }
+}
+
+impl Default for MemIo {
+    fn default() -> MemIo {
+        MemIo::new()
+    }
+}
+
+impl SocketError for MemIo {
+    fn take_socket_error(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Formats bytes as a canonical hex+ASCII dump (like `hexdump -C`)
+struct HexDump<'a>(&'a [u8]);
+
+impl<'a> fmt::Debug for HexDump<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(fmt, "[]");
+        }
+        hex_lines(fmt, self.0, 0)
+    }
+}
+
+fn hex_lines(fmt: &mut fmt::Formatter, data: &[u8], start: usize)
+    -> fmt::Result
+{
+    for (idx, line) in data.chunks(16).enumerate() {
+        write!(fmt, "\n{:08x} ", start + idx*16)?;
+        for i in 0..16 {
+            if i == 8 {
+                write!(fmt, " ")?;
+            }
+            match line.get(i) {
+                Some(b) => write!(fmt, " {:02x}", b)?,
+                None => write!(fmt, "   ")?,
+            }
+        }
+        write!(fmt, "  |")?;
+        for &b in line {
+            if (0x20..0x7f).contains(&b) {
+                write!(fmt, "{}", b as char)?;
+            } else {
+                write!(fmt, ".")?;
+            }
+        }
+        write!(fmt, "|")?;
+    }
+    Ok(())
+}
+
+/// Shows the buffer (as in `Debug` of `MemIo`) eliding the middle part
+struct Elided<'a> {
+    data: &'a [u8],
+    limit: Option<(usize, usize)>,
+    hex: bool,
+}
+
+impl<'a> fmt::Debug for Elided<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let len = self.data.len();
+        let (head, tail) = match self.limit {
+            Some((head, tail)) if head + tail < len => (head, tail),
+            _ if self.hex => return HexDump(self.data).fmt(fmt),
+            _ => return String::from_utf8_lossy(self.data).fmt(fmt),
+        };
+        let skipped = len - head - tail;
+        if self.hex {
+            hex_lines(fmt, &self.data[..head], 0)?;
+            write!(fmt, "\n... {} bytes skipped ...", skipped)?;
+            hex_lines(fmt, &self.data[len - tail..], len - tail)
+        } else {
+            write!(fmt, "{:?}... {} bytes skipped ...{:?}",
+                String::from_utf8_lossy(&self.data[..head]), skipped,
+                String::from_utf8_lossy(&self.data[len - tail..]))
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let bufs = match self.bufs.lock() {
+            Ok(bufs) => bufs,
+            Err(_) => return,
+        };
+        // Double panic would abort the whole test process
+        if !bufs.strict || thread::panicking() {
+            return;
+        }
+        if !bufs.input.is_empty() || !bufs.delayed_input.is_empty() ||
+            !bufs.script.is_empty() || bufs.output_unchecked() ||
+            !bufs.pending_output.is_empty() || !bufs.generated.is_empty()
+        {
+            let generated: u64 = bufs.generated.iter()
+                .map(|g| g.len - g.offset).sum();
+            let pending: Vec<u8> = bufs.pending_output.iter()
+                .flat_map(|(_, data)| data.iter().cloned()).collect();
+            panic!("MemIo (mock stream) in strict mode is dropped with \
+                    leftover data.\n\
+                    Unread input: {:#?}\n\
+                    Delayed input chunks: {}\n\
+                    Unread generated input: {} bytes\n\
+                    Remaining script: {:?}\n\
+                    Unchecked output: {:#?}\n\
+                    Delayed output: {:#?}",
+                HexDump(&bufs.input), bufs.delayed_input.len(), generated,
+                bufs.script, HexDump(&bufs.output), HexDump(&pending));
+        }
+    }
+}
+
+/// An address to "connect" `MemIo` streams to
+///
+/// This is an `ActiveStream::Address` of the `MemIo`, so it can be used with
+/// `rotor_stream::Persistent` and `Stream::connected`. Each connect takes the
+/// next stream added by `add_stream()`, when there are no streams left
+/// connect fails with `ConnectionRefused`.
+#[derive(Clone, Debug)]
+pub struct MemConnector(Arc<Mutex<VecDeque<MemIo>>>);
+
+impl MemConnector {
+    /// Create a connector with no streams
+    pub fn new() -> MemConnector {
+        MemConnector(Arc::new(Mutex::new(VecDeque::new())))
+    }
+    /// Add a stream to be returned by one of the next connects
+    ///
+    /// You should keep a clone of the stream to drive the test.
+    pub fn add_stream(&self, io: MemIo) {
+        self.0.lock().expect("Poisoned MemConnector").push_back(io);
+    }
+    /// Number of streams which are not connected yet
+    pub fn streams_left(&self) -> usize {
+        self.0.lock().expect("Poisoned MemConnector").len()
+    }
+}
+
+impl Default for MemConnector {
+    fn default() -> MemConnector {
+        MemConnector::new()
+    }
+}
+
+impl ActiveStream for MemIo {
+    type Address = MemConnector;
+    fn connect(addr: &MemConnector) -> io::Result<MemIo> {
+        addr.0.lock().expect("Poisoned MemConnector").pop_front()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionRefused,
+            "no more mock streams in the connector"))
+    }
+}
+
+impl fmt::Debug for MemIo {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let bufs = self.bufs();
+        // Pretty-printed version is used for binary protocols
+        let hex = fmt.alternate();
+        fmt.debug_struct("MemIo")
+        .field("input", &Elided {
+            data: &bufs.input, limit: bufs.debug_limit, hex })
+        .field("input_closed", &bufs.input_closed)
+        .field("output", &Elided {
+            data: &bufs.output, limit: bufs.debug_limit, hex })
+        .field("output_closed", &bufs.output_closed)
+        .finish()
+    }
+}
+
+impl Bufs {
+    fn fired_watermarks(&mut self) -> Option<(Vec<u8>, Vec<OutputCallback>)>
+    {
+        if self.watermarks.is_empty() {
+            return None;
+        }
+        let mut fired = Vec::new();
+        let mut i = 0;
+        while i < self.watermarks.len() {
+            let hit = match self.watermarks[i].0 {
+                Watermark::Size(n) => self.output.len() >= n,
+                Watermark::Delimiter(ref d) => {
+                    self.output.windows(d.len()).any(|w| w == &d[..])
+                }
+            };
+            if hit {
+                fired.push(self.watermarks.remove(i).1);
+            } else {
+                i += 1;
+            }
+        }
+        if !fired.is_empty() {
+            Some((self.output.clone(), fired))
+        } else {
+            None
+        }
+    }
+    fn delay_input(&mut self, time: Time, data: Vec<u8>) {
+        let pos = self.delayed_input.iter()
+            .position(|&(t, _)| t > time)
+            .unwrap_or(self.delayed_input.len());
+        self.delayed_input.insert(pos, (time, data));
+    }
+    fn release_input(&mut self) {
+        let now = self.now();
+        while self.delayed_input.front().map(|&(t, _)| t <= now)
+            .unwrap_or(false)
+        {
+            let (_, data) = self.delayed_input.pop_front().unwrap();
+            self.push_chunk(&data);
+        }
+        self.signal_readable();
+    }
+    // Marks the token readable in the `MockLoop` the stream is registered in
+    fn signal_readable(&self) {
+        let mut events = mio::EventSet::none();
+        if !self.input.is_empty() || !self.generated.is_empty() ||
+            self.input_closed
+        {
+            events = events | mio::EventSet::readable();
+        }
+        if self.input_closed {
+            events = events | mio::EventSet::hup();
+        }
+        self.registration.signal(events);
+    }
+    fn check_input_limit(&self, extra: u64) {
+        let generated: u64 = self.generated.iter()
+            .map(|g| g.len - g.offset).sum();
+        let unread = self.input.len() as u64 + generated + extra;
+        if unread > self.input_limit as u64 {
+            panic!("Input limit of the mock stream exceeded: {} bytes \
+                    of {} are not read by the application",
+                    unread, self.input_limit);
+        }
+    }
+    fn check_output_limit(&self, size: usize) {
+        if size <= self.output_limit {
+            return;
+        }
+        let head = min(self.output.len(), 256);
+        let tail = min(self.output.len() - head, 256);
+        panic!("Output limit of the mock stream exceeded: {} bytes of {} \
+                are written. Is application stuck in a write loop?\n\
+                Output starts with: {:#?}\n\
+                ({} bytes skipped)\n\
+                Output ends with: {:#?}",
+            size, self.output_limit,
+            HexDump(&self.output[..head]),
+            self.output.len() - head - tail,
+            HexDump(&self.output[self.output.len() - tail..]));
+    }
+    fn push_chunk(&mut self, chunk: &[u8]) {
+        // Data pushed before is a separate chunk too
+        let chunked: usize = self.input_chunks.iter().sum();
+        let loose = self.input.len() - chunked;
+        if loose > 0 {
+            self.input_chunks.push_back(loose);
+        }
+        if !chunk.is_empty() {
+            self.check_input_limit(chunk.len() as u64);
+            self.input.extend(chunk);
+            self.input_chunks.push_back(chunk.len());
+        }
+    }
+    // Returns number of bytes for the next read given `max` is available
+    fn fragment(&mut self, max: usize) -> usize {
+        let read = self.stats.bytes_read;
+        while self.split_points.front().map(|&x| x <= read).unwrap_or(false) {
+            self.split_points.pop_front();
+        }
+        let (bytes, random) = self.next_fragment(max);
+        self.random_chunking = random;
+        if let Some((ref mut left, _)) = self.read_failure {
+            *left -= bytes;
+        }
+        bytes
+    }
+    // Same as `fragment()` but doesn't change anything, the new state of
+    // random chunking is returned instead
+    fn next_fragment(&self, max: usize) -> (usize, Option<u64>) {
+        let mut max = min(max, self.read_chunk);
+        let read = self.stats.bytes_read;
+        if let Some(&point) = self.split_points.iter().find(|&&x| x > read) {
+            max = min(max as u64, point - read) as usize;
+        }
+        if let Some(&(left, _)) = self.read_failure.as_ref() {
+            max = min(max, left);
+        }
+        if max == 0 {
+            return (0, self.random_chunking);
+        }
+        match self.random_chunking {
+            Some(mut state) => {
+                // splitmix64, good enough for the tests and doesn't require
+                // any external dependencies
+                state = state.wrapping_add(0x9E3779B97F4A7C15);
+                let mut z = state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+                z = z ^ (z >> 31);
+                (1 + (z % max as u64) as usize, Some(state))
+            }
+            None => (max, None),
+        }
+    }
+    // Bytes at the start of the input buffer which the next read may return
+    fn available(&self) -> usize {
+        let mut available = self.input.len();
+        if let Some(&chunk) = self.input_chunks.front() {
+            available = chunk;
+        }
+        if let Some(gen) = self.generated.front() {
+            available = min(available, gen.before);
+        }
+        if self.line_buffered {
+            let newline = self.input[..available].iter()
+                .position(|&x| x == b'\n');
+            available = match newline {
+                Some(pos) => pos + 1,
+                None if self.input_closed => available,
+                None => 0,
+            };
+        }
+        available
+    }
+    fn mark_output_checked(&mut self) {
+        self.output_checked = self.output_consumed + self.output.len() as u64;
+    }
+    fn output_unchecked(&self) -> bool {
+        self.output_consumed + self.output.len() as u64 > self.output_checked
+    }
+    // Time of the `MockLoop` the stream is registered in, if any
+    fn now(&self) -> Time {
+        match self.registration.mock_loop() {
+            Some(state) => state.lock().expect("Poisoned MockLoop").now(),
+            None => self.now,
+        }
+    }
+    fn record(&mut self, event: IoEvent) {
+        if self.observer.is_some() {
+            self.observed.push(event.clone());
+        }
+        let now = self.now();
+        self.timeline.push((next_seq(), now, event));
+    }
+    fn reset_connection(&mut self) {
+        self.reset = true;
+        self.input.clear();
+        self.input_chunks.clear();
+        self.delayed_input.clear();
+        self.generated.clear();
+        self.record(IoEvent::Reset);
+    }
+    fn interrupted(&self, call_no: usize) -> bool {
+        self.interrupt_every > 0 && call_no.is_multiple_of(self.interrupt_every)
+    }
+    fn read(&mut self, val: &mut [u8]) -> io::Result<usize> {
+        if self.reset {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset,
+                "mocked connection is reset"));
+        }
+        if self.interrupted(self.stats.reads + 1) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted,
+                "mocked interruption"));
+        }
+        if let Some(err) = self.read_errors.pop_front() {
+            return Err(err);
+        }
+        if self.would_block > 0 {
+            self.would_block -= 1;
+            return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                "mocked would block"));
+        }
+        if let Some((0, _)) = self.read_failure {
+            return Err(self.read_failure.take().unwrap().1);
+        }
+        if let Some(step) = self.script.pop_front() {
+            return match step {
+                IoStep::Read(mut data) => {
+                    let bytes = self.fragment(min(val.len(), data.len()));
+                    assert_eq!(io::copy(
+                        &mut io::Cursor::new(&data[..bytes]),
+                        &mut io::Cursor::new(val))
+                        .expect("copy always work"), bytes as u64);
+                    if bytes < data.len() {
+                        data.drain(..bytes);
+                        self.script.push_front(IoStep::Read(data));
+                    }
+                    Ok(bytes)
+                }
+                IoStep::WouldBlock => Err(io::Error::new(
+                    io::ErrorKind::WouldBlock, "scripted would block")),
+                IoStep::Error(kind) => Err(io::Error::new(kind,
+                    "scripted error")),
+                IoStep::Eof => Ok(0),
+                step => panic!("application tries to read, \
+                                but the script expects {:?}", step),
+            };
+        }
+        if self.generated.front().map(|g| g.before == 0).unwrap_or(false) {
+            return Ok(self.read_generated(val));
+        }
+        let available = self.available();
+        let bytes = self.fragment(min(val.len(), available));
+        if bytes > 0 {
+            assert_eq!(io::copy(
+                &mut io::Cursor::new(&self.input[..bytes]),
+                &mut io::Cursor::new(val))
+                .expect("copy always work"), bytes as u64);
+            self.input.drain(..bytes);
+            for gen in self.generated.iter_mut() {
+                gen.before -= bytes;
+            }
+            if let Some(chunk) = self.input_chunks.pop_front() {
+                if chunk > bytes {
+                    self.input_chunks.push_front(chunk - bytes);
+                }
+            }
+            Ok(bytes)
+        } else {
+            if self.input_closed {
+                Ok(0)
+            } else {
+                Err(io::Error::new(io::ErrorKind::WouldBlock,
+                    "no data in mocked input buffer"))
+            }
+        }
+    }
+    fn read_generated(&mut self, val: &mut [u8]) -> usize {
+        let max = {
+            let gen = self.generated.front().unwrap();
+            min(val.len() as u64, gen.len - gen.offset) as usize
+        };
+        let bytes = self.fragment(max);
+        let mut gen = self.generated.pop_front().unwrap();
+        gen.fill(&mut val[..bytes]);
+        gen.offset += bytes as u64;
+        if gen.offset < gen.len {
+            self.generated.push_front(gen);
+        }
+        bytes
+    }
+    fn write(&mut self, val: &[u8]) -> io::Result<usize> {
+        if self.reset {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset,
+                "mocked connection is reset"));
+        }
+        let now = self.now();
+        if self.write_deadline.map(|d| now >= d).unwrap_or(false) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                "mocked write deadline reached"));
+        }
+        if self.interrupted(self.stats.writes + 1) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted,
+                "mocked interruption"));
+        }
+        if let Some(err) = self.write_errors.pop_front() {
+            return Err(err);
+        }
+        if self.would_block > 0 {
+            self.would_block -= 1;
+            return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                "mocked would block"));
+        }
+        if self.output_closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                "mocked output is shut down"));
+        }
+        let mut limit = self.write_chunk;
+        match self.script.pop_front() {
+            None => {}
+            Some(IoStep::Write(n)) => limit = min(limit, n),
+            Some(IoStep::WouldBlock) => {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                    "scripted would block"));
+            }
+            Some(IoStep::Error(kind)) => {
+                return Err(io::Error::new(kind, "scripted error"));
+            }
+            Some(step) => panic!("application tries to write, \
+                                  but the script expects {:?}", step),
+        }
+        let pending: usize = self.pending_output.iter()
+            .map(|(_, data)| data.len()).sum();
+        let room = self.output_capacity
+            .saturating_sub(self.output.len() + pending);
+        if room == 0 && !val.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                "mocked output buffer is full"));
+        }
+        let bytes = min(min(val.len(), limit), room);
+        self.check_output_limit(self.output.len() + pending + bytes);
+        if self.write_latency == Duration::new(0, 0) {
+            self.show_output(&val[..bytes]);
+        } else {
+            let time = now + self.write_latency;
+            self.pending_output.push_back((time, val[..bytes].to_vec()));
+        }
+        Ok(bytes)
+    }
+    fn show_output(&mut self, data: &[u8]) {
+        self.output.extend(data);
+        if self.peer.is_some() {
+            self.to_peer.extend(data);
+        }
+    }
+    fn release_output(&mut self, all: bool) {
+        let now = self.now();
+        while self.pending_output.front()
+            .map(|&(t, _)| all || t <= now).unwrap_or(false)
+        {
+            let (_, data) = self.pending_output.pop_front().unwrap();
+            self.show_output(&data);
+        }
+    }
+}
+
+impl IoStats {
+    fn count(&mut self, result: &io::Result<usize>) {
+        match *result {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.would_blocks += 1;
+            }
+            Err(_) => self.errors += 1,
+        }
+    }
+}
+
+impl io::Read for MemIo {
+    fn read(&mut self, val: &mut [u8]) -> io::Result<usize> {
+        let mut bufs = self.bufs();
+        let result = bufs.read(val);
+        bufs.stats.reads += 1;
+        bufs.stats.count(&result);
+        let event = match result {
+            Ok(bytes) => {
+                bufs.stats.bytes_read += bytes as u64;
+                IoEvent::Read(bytes)
+            }
+            Err(ref e) => IoEvent::ReadError(e.kind()),
+        };
+        bufs.record(event);
+        drop(bufs);
+        self.run_observer();
+        result
+    }
+}
+impl io::Write for MemIo {
+    fn write(&mut self, val: &[u8]) -> io::Result<usize> {
+        let mut bufs = self.bufs();
+        let result = bufs.write(val);
+        bufs.stats.writes += 1;
+        bufs.stats.count(&result);
+        let event = match result {
+            Ok(bytes) => {
+                bufs.stats.bytes_written += bytes as u64;
+                IoEvent::Write(val[..bytes].to_vec())
+            }
+            Err(ref e) => IoEvent::WriteError(e.kind()),
+        };
+        bufs.record(event);
+        self.output_changed(bufs);
+        self.run_observer();
+        result
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        let result = {
+            let mut bufs = self.bufs();
+            let offset = bufs.stats.bytes_written;
+            bufs.flushes.push(offset);
+            bufs.record(IoEvent::Flush);
+            if let Some(err) = bufs.flush_errors.pop_front() {
+                Err(err)
+            } else if let Some((kind, ref msg)) = bufs.flush_failure {
+                Err(io::Error::new(kind, msg.clone()))
+            } else {
+                Ok(())
+            }
+        };
+        self.run_observer();
+        result
     }
-    fn flush(&mut self) -> io::Result<()> { Ok(()) }
 }
 
 impl mio::Evented for MemIo {
     fn register(&self, _selector: &mut mio::Selector,
-        _token: mio::Token, _interest: mio::EventSet, _opts: mio::PollOpt)
+        token: mio::Token, interest: mio::EventSet, opts: mio::PollOpt)
         -> io::Result<()>
-    { unreachable!("trying to poll on mock stream") }
-    fn reregister(&self, _selector: &mut mio::Selector, _token: mio::Token,
-        _interest: mio::EventSet, _opts: mio::PollOpt) -> io::Result<()>
-    { unreachable!("trying to poll on mock stream") }
+    {
+        let mut bufs = self.bufs();
+        bufs.registration.register(token, interest, opts)?;
+        // like edge-triggered socket, report input which is already there
+        bufs.release_input();
+        if let Some(state) = bufs.registration.mock_loop() {
+            let stream = Arc::downgrade(&self.0);
+            state.lock().expect("Poisoned MockLoop")
+            .watch_clock(Box::new(move |_| {
+                let stream = match stream.upgrade() {
+                    Some(stream) => MemIo(stream),
+                    None => return false,
+                };
+                let bufs = stream.bufs();
+                if bufs.registration.mock_loop().is_none() {
+                    return false;
+                }
+                stream.time_changed(bufs);
+                true
+            }));
+        }
+        Ok(())
+    }
+    fn reregister(&self, _selector: &mut mio::Selector, token: mio::Token,
+        interest: mio::EventSet, opts: mio::PollOpt) -> io::Result<()>
+    {
+        let mut bufs = self.bufs();
+        bufs.registration.reregister(token, interest, opts)?;
+        bufs.release_input();
+        Ok(())
+    }
     fn deregister(&self, _selector: &mut mio::Selector) -> io::Result<()>
-    { unreachable!("trying to poll on mock stream") }
+    {
+        self.bufs().registration.deregister()
+    }
 }
 
 #[cfg(test)]
 mod self_test {
-    use std::io::{Read, Write};
-    use super::MemIo;
+    use std::env;
+    use std::fs::remove_file;
+    use std::io::{Read, Write, Error, ErrorKind};
+    use rotor_stream::{StreamSocket, ActiveStream};
+    use super::{MemIo, IoStep, IoStats, IoEvent, MemConnector};
+    use super::{SocketOptions};
 
     #[test]
     fn input() {
@@ -153,9 +1964,992 @@ mod self_test {
     #[test]
     fn output() {
         let mut s = MemIo::new();
-        s.write(b"hello").expect("write failed");
-        s.write(b"world").expect("write failed");
+        assert_eq!(s.write(b"hello").expect("write failed"), 5);
+        assert_eq!(s.write(b"world").expect("write failed"), 5);
         assert_eq!(s.output_str(), "helloworld");
     }
 
+    #[test]
+    fn take_output() {
+        let mut s = MemIo::new();
+        assert_eq!(s.write(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap(), 25);
+        assert_eq!(s.take_output(), b"HTTP/1.1 100 Continue\r\n\r\n");
+        assert_eq!(s.take_output(), b"");
+        assert_eq!(s.write(b"HTTP/1.1 200 OK\r\n").unwrap(), 17);
+        assert_eq!(s.take_output(), b"HTTP/1.1 200 OK\r\n");
+        assert_eq!(s.output_bytes(), b"");
+    }
+
+    #[test]
+    fn with_output() {
+        let mut s = MemIo::new();
+        assert!(s.with_output(|x| x.is_empty()));
+        assert_eq!(s.write(b"hello").unwrap(), 5);
+        assert_eq!(s.write(b" world").unwrap(), 6);
+        assert!(s.with_output(|x| x == b"hello world"));
+        assert_eq!(s.with_output(|x| x[6..].to_vec()), b"world");
+        // the output is kept
+        assert_eq!(s.output_str(), "hello world");
+    }
+
+    #[test]
+    fn read_chunks() {
+        let mut s = MemIo::new();
+        s.push_bytes("hello world");
+        s.set_read_chunk_limit(4);
+        let mut buf = [0u8; 16];
+        assert_eq!(s.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"hell");
+        assert_eq!(s.read(&mut buf).unwrap(), 4);
+        assert_eq!(s.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"rld");
+    }
+
+    #[test]
+    fn trickle() {
+        let mut s = MemIo::trickle();
+        s.push_bytes("ab");
+        let mut buf = [0u8; 16];
+        assert_eq!(s.read(&mut buf).unwrap(), 1);
+        assert_eq!(s.read(&mut buf).unwrap(), 1);
+        assert_eq!(&buf[..1], b"b");
+    }
+
+    #[test]
+    fn random_chunking() {
+        fn chunks(seed: u64) -> Vec<usize> {
+            let mut s = MemIo::new();
+            s.push_bytes(&[0u8; 100][..]);
+            s.shutdown_input();
+            s.set_random_chunking(seed);
+            let mut buf = [0u8; 100];
+            let mut result = Vec::new();
+            loop {
+                match s.read(&mut buf).unwrap() {
+                    0 => return result,
+                    n => result.push(n),
+                }
+            }
+        }
+        let a = chunks(1);
+        assert_eq!(a.iter().sum::<usize>(), 100);
+        assert!(a.len() > 1);
+        assert_eq!(a, chunks(1));
+        assert!(a != chunks(2));
+    }
+
+    #[test]
+    fn output_lines() {
+        let mut s = MemIo::new();
+        assert_eq!(s.write(b"+OK\r\n-ERR bad\r\nPARTIAL").unwrap(), 22);
+        assert_eq!(s.output_lines(), vec!["+OK", "-ERR bad", "PARTIAL"]);
+    }
+
+    #[test]
+    fn chunks() {
+        let mut s = MemIo::new();
+        s.push_bytes("GET");
+        s.push_chunks(vec![" / HTTP/1.1\r", "\n", "\r\n"]);
+        s.push_bytes("body");
+        let mut buf = [0u8; 64];
+        assert_eq!(s.read(&mut buf).unwrap(), 3);
+        assert_eq!(s.read(&mut buf).unwrap(), 12);
+        assert_eq!(s.read(&mut buf).unwrap(), 1);
+        assert_eq!(s.read(&mut buf).unwrap(), 2);
+        assert_eq!(s.read(&mut buf).unwrap(), 4);
+    }
+
+    #[test]
+    fn files() {
+        let path = env::temp_dir().join("rotor-test-memio-fixture.bin");
+        let mut s = MemIo::new();
+        assert_eq!(s.write(b"\x00fixture\xff").unwrap(), 9);
+        s.write_output_to(&path).unwrap();
+        let mut t = MemIo::new();
+        t.push_file(&path).unwrap();
+        remove_file(&path).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(t.read(&mut buf).unwrap(), 9);
+        assert_eq!(&buf[..9], b"\x00fixture\xff");
+    }
+
+    #[test]
+    fn pcap() {
+        use std::fs::File;
+        use pcap::self_test::capture;
+
+        let path = env::temp_dir().join("rotor-test-memio-fixture.pcap");
+        let data = capture(&[
+            (0, [10, 0, 0, 1], 5555, [10, 0, 0, 2], 80, 1, b"GET"),
+            (1, [10, 0, 0, 2], 80, [10, 0, 0, 1], 5555, 1, b"ignored"),
+            (2, [10, 0, 0, 1], 5555, [10, 0, 0, 2], 80, 4, b" /"),
+        ]);
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        let mut s = MemIo::new();
+        s.push_pcap(&path, "10.0.0.1:5555".parse().unwrap()).unwrap();
+        remove_file(&path).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(s.read(&mut buf).unwrap(), 3);
+        assert_eq!(s.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b" /");
+    }
+
+    #[test]
+    fn pcap_timed() {
+        use std::fs::File;
+        use std::time::Duration;
+        use rotor::Time;
+        use pcap::self_test::capture;
+
+        let path = env::temp_dir().join("rotor-test-memio-timed.pcap");
+        let data = capture(&[
+            (1000, [10, 0, 0, 1], 5555, [10, 0, 0, 2], 80, 1, b"GET"),
+            (1500, [10, 0, 0, 1], 5555, [10, 0, 0, 2], 80, 4, b" /"),
+        ]);
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        let mut s = MemIo::new();
+        s.push_pcap_timed(&path, "10.0.0.1:5555".parse().unwrap()).unwrap();
+        remove_file(&path).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(s.read(&mut buf).unwrap(), 3);
+        assert_eq!(s.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        s.set_time(Time::zero() + Duration::from_millis(499));
+        assert!(s.read(&mut buf).is_err());
+        s.set_time(Time::zero() + Duration::from_millis(500));
+        assert_eq!(s.read(&mut buf).unwrap(), 2);
+    }
+
+    #[test]
+    fn watermarks() {
+        use std::sync::{Arc, Mutex};
+
+        let mut s = MemIo::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let l1 = log.clone();
+        s.on_output_size(5, move |data| {
+            l1.lock().unwrap().push(format!("size {}", data.len()));
+        });
+        let l2 = log.clone();
+        s.on_output_delimiter("\r\n", move |data| {
+            l2.lock().unwrap().push(format!("line {}", data.len()));
+        });
+        assert_eq!(s.write(b"abc").unwrap(), 3);
+        assert_eq!(log.lock().unwrap().len(), 0);
+        assert_eq!(s.write(b"de\r").unwrap(), 3);
+        assert_eq!(s.write(b"\nfg").unwrap(), 3);
+        assert_eq!(s.write(b"\r\n").unwrap(), 2);
+        assert_eq!(*log.lock().unwrap(), vec!["size 6", "line 9"]);
+    }
+
+    #[test]
+    fn wait_for_output() {
+        use std::thread;
+        use std::time::Duration;
+
+        let s = MemIo::new();
+        let mut app = s.clone();
+        let thread = thread::spawn(move || {
+            assert_eq!(app.write(b"hello").unwrap(), 5);
+            assert_eq!(app.write(b" world").unwrap(), 6);
+        });
+        assert!(s.wait_for_output(|x| x.ends_with(b"world"),
+                                  Duration::new(10, 0)));
+        thread.join().unwrap();
+        assert!(!s.wait_for_output(|x| x.len() > 100,
+                                   Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn strict_ok() {
+        let mut s = MemIo::new();
+        s.set_strict(true);
+        s.push_bytes("ping");
+        let mut buf = [0u8; 4];
+        assert_eq!(s.read(&mut buf).unwrap(), 4);
+        assert_eq!(s.write(b"pong").unwrap(), 4);
+        assert_eq!(s.take_output(), b"pong");
+    }
+
+    #[test]
+    fn strict_inspected() {
+        let mut s = MemIo::new();
+        s.set_strict(true);
+        assert_eq!(s.write(b"pong").unwrap(), 4);
+        assert_eq!(s.output_str(), "pong");
+    }
+
+    #[test]
+    #[should_panic(expected="leftover data")]
+    fn strict_unchecked() {
+        let mut s = MemIo::new();
+        s.set_strict(true);
+        assert_eq!(s.write(b"pong").unwrap(), 4);
+        assert_eq!(s.output_str(), "pong");
+        assert_eq!(s.write(b"!").unwrap(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected="Unread generated input: 100 bytes")]
+    fn strict_generated() {
+        let mut s = MemIo::new();
+        s.set_strict(true);
+        s.push_generated(100, |_, buf| for b in buf { *b = b'x' });
+    }
+
+    #[test]
+    #[should_panic(expected="Delayed output: \n00000000  70 6f 6e 67")]
+    fn strict_delayed_output() {
+        use std::time::Duration;
+
+        let mut s = MemIo::new();
+        s.set_strict(true);
+        s.set_write_latency(Duration::from_millis(100));
+        assert_eq!(s.write(b"pong").unwrap(), 4);
+        assert_eq!(s.output_str(), "");
+    }
+
+    #[test]
+    #[should_panic(expected="leftover data")]
+    fn strict_unread() {
+        let mut s = MemIo::new();
+        s.set_strict(true);
+        s.push_bytes("ping");
+        let copy = s.clone();
+        drop(s);
+        drop(copy);
+    }
+
+    #[test]
+    fn addresses() {
+        let s = MemIo::new();
+        assert_eq!(s.peer_addr().unwrap_err().kind(), ErrorKind::NotConnected);
+        s.set_peer_addr("10.1.2.3:4567".parse().unwrap());
+        s.set_local_addr("127.0.0.1:80".parse().unwrap());
+        assert_eq!(s.peer_addr().unwrap(), "10.1.2.3:4567".parse().unwrap());
+        assert_eq!(s.local_addr().unwrap(), "127.0.0.1:80".parse().unwrap());
+    }
+
+    #[test]
+    fn connect() {
+        fn is_socket<S: StreamSocket + ActiveStream>() {}
+        is_socket::<MemIo>();
+
+        let addr = MemConnector::new();
+        let mut s = MemIo::new();
+        s.push_bytes("hello");
+        addr.add_stream(s.clone());
+        let mut conn = MemIo::connect(&addr).unwrap();
+        assert_eq!(addr.streams_left(), 0);
+        let mut buf = [0u8; 16];
+        assert_eq!(conn.read(&mut buf).unwrap(), 5);
+        assert_eq!(MemIo::connect(&addr).unwrap_err().kind(),
+                   ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn socket_options() {
+        let s = MemIo::new();
+        s.set_nodelay(true).unwrap();
+        s.set_keepalive(Some(60)).unwrap();
+        assert_eq!(s.socket_options(), SocketOptions {
+            nodelay: Some(true),
+            keepalive: Some(Some(60)),
+            linger: None,
+        });
+    }
+
+    #[test]
+    fn interrupts() {
+        let mut s = MemIo::new();
+        s.set_interrupt_every(2);
+        s.push_bytes("hello");
+        s.shutdown_input();
+        // read_to_string retries on Interrupted
+        let mut data = String::new();
+        s.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "hello");
+        assert_eq!(s.write(b"a").unwrap(), 1);
+        assert_eq!(s.write(b"b").unwrap_err().kind(), ErrorKind::Interrupted);
+        assert_eq!(s.write(b"b").unwrap(), 1);
+        assert_eq!(s.stats().errors, 2);
+    }
+
+    #[test]
+    fn fail_read_after() {
+        let mut s = MemIo::new();
+        s.push_bytes("hello world");
+        s.fail_read_after(7, Error::new(ErrorKind::ConnectionAborted, "x"));
+        let mut buf = [0u8; 5];
+        assert_eq!(s.read(&mut buf).unwrap(), 5);
+        assert_eq!(s.read(&mut buf).unwrap(), 2);
+        assert_eq!(s.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::ConnectionAborted);
+        assert_eq!(s.read(&mut buf).unwrap(), 4);
+    }
+
+    #[test]
+    fn checkpoint() {
+        let mut s = MemIo::new();
+        s.push_bytes("abc");
+        let cp = s.checkpoint();
+        for _ in 0..2 {
+            let mut buf = [0u8; 16];
+            assert_eq!(s.read(&mut buf).unwrap(), 3);
+            assert_eq!(s.write(b"x").unwrap(), 1);
+            s.shutdown_output();
+            assert_eq!(s.output_str(), "x");
+            s.rollback(&cp);
+            assert_eq!(s.output_str(), "");
+        }
+    }
+
+    #[test]
+    fn checkpoint_delayed_input() {
+        use std::time::Duration;
+        use rotor::Time;
+        let mut s = MemIo::new();
+        s.push_bytes_after(Duration::from_millis(100), "hello");
+        let cp = s.checkpoint();
+        s.set_time(Time::zero() + Duration::from_millis(100));
+        let mut buf = [0u8; 16];
+        assert_eq!(s.read(&mut buf).unwrap(), 5);
+        s.rollback(&cp);
+        assert_eq!(s.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+    }
+
+    #[test]
+    fn checkpoint_split_points() {
+        let mut s = MemIo::new();
+        s.push_bytes("0123456789");
+        s.set_split_points(&[2, 5]);
+        s.fail_read_after(7, Error::other("boom"));
+        let cp = s.checkpoint();
+        for _ in 0..2 {
+            let mut buf = [0u8; 16];
+            assert_eq!(s.read(&mut buf).unwrap(), 2);
+            assert_eq!(s.read(&mut buf).unwrap(), 3);
+            assert_eq!(s.read(&mut buf).unwrap(), 2);
+            assert_eq!(s.read(&mut buf).unwrap_err().to_string(), "boom");
+            assert_eq!(s.read(&mut buf).unwrap(), 3);
+            s.rollback(&cp);
+        }
+    }
+
+    #[test]
+    fn checkpoint_generated() {
+        let mut s = MemIo::new();
+        s.push_bytes("ab");
+        s.push_generated(3, |off, buf| {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = b'0' + off as u8 + i as u8;
+            }
+        });
+        s.push_bytes("c");
+        let cp = s.checkpoint();
+        for _ in 0..2 {
+            let mut buf = [0u8; 16];
+            assert_eq!(s.read(&mut buf).unwrap(), 2);
+            assert_eq!(s.read(&mut buf).unwrap(), 3);
+            assert_eq!(&buf[..3], b"012");
+            assert_eq!(s.read(&mut buf).unwrap(), 1);
+            assert_eq!(&buf[..1], b"c");
+            s.rollback(&cp);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected="Input limit of the mock stream exceeded")]
+    fn input_limit() {
+        let mut s = MemIo::new();
+        s.set_input_limit(10);
+        s.push_bytes("hello");
+        let mut buf = [0u8; 16];
+        assert_eq!(s.read(&mut buf).unwrap(), 5);
+        s.push_bytes("0123456789");
+        s.push_bytes("!");
+    }
+
+    #[test]
+    #[should_panic(expected="Input limit of the mock stream exceeded")]
+    fn input_limit_generated() {
+        let mut s = MemIo::new();
+        s.set_input_limit(10);
+        s.push_generated(8, |_, buf| for b in buf { *b = b'x' });
+        s.push_bytes("abc");
+    }
+
+    #[test]
+    #[should_panic(expected="Input limit of the mock stream exceeded")]
+    fn input_limit_peer() {
+        let (mut a, b) = MemIo::pair();
+        b.set_input_limit(4);
+        assert_eq!(a.write(b"hello").unwrap(), 5);
+    }
+
+    #[test]
+    fn flushes() {
+        let mut s = MemIo::new();
+        assert_eq!(s.write(b"hello\n").unwrap(), 6);
+        s.flush().unwrap();
+        s.take_output();
+        assert_eq!(s.write(b"world\n").unwrap(), 6);
+        s.flush().unwrap();
+        assert_eq!(s.flush_count(), 2);
+        assert_eq!(s.flush_offsets(), vec![6, 12]);
+    }
+
+    #[test]
+    fn frames() {
+        let mut s = MemIo::new();
+        assert_eq!(s.write(b"one\x00\x00two\x00thr").unwrap(), 12);
+        assert_eq!(s.output_frames(b"\x00"), vec![
+            b"one".to_vec(), b"".to_vec(), b"two".to_vec(), b"thr".to_vec()]);
+        assert_eq!(s.output_fixed_frames(6), vec![
+            b"one\x00\x00t".to_vec(), b"wo\x00thr".to_vec()]);
+    }
+
+    #[test]
+    fn write_deadline() {
+        use std::time::Duration;
+        use rotor::Time;
+
+        let mut s = MemIo::new();
+        let deadline = Time::zero() + Duration::new(1, 0);
+        s.set_write_deadline(Some(deadline));
+        assert_eq!(s.write(b"a").unwrap(), 1);
+        s.set_time(deadline);
+        assert_eq!(s.write(b"b").unwrap_err().kind(), ErrorKind::TimedOut);
+        s.set_write_deadline(None);
+        assert_eq!(s.write(b"c").unwrap(), 1);
+    }
+
+    #[test]
+    fn write_latency() {
+        use std::time::Duration;
+        use rotor::Time;
+
+        let (mut a, mut b) = MemIo::pair();
+        a.set_write_latency(Duration::from_millis(100));
+        assert_eq!(a.write(b"hello").unwrap(), 5);
+        assert_eq!(a.output_str(), "");
+        let mut buf = [0u8; 16];
+        assert!(b.read(&mut buf).is_err());
+        a.set_time(Time::zero() + Duration::from_millis(100));
+        assert_eq!(a.output_str(), "hello");
+        assert_eq!(b.read(&mut buf).unwrap(), 5);
+        assert_eq!(a.write(b" world").unwrap(), 6);
+        a.tick();
+        assert_eq!(a.output_str(), "hello world");
+    }
+
+    #[test]
+    fn push_after() {
+        use std::time::Duration;
+        use rotor::Time;
+
+        let mut io = MemIo::new();
+        io.push_bytes_after(Duration::from_millis(200), "world");
+        io.push_bytes_after(Duration::from_millis(100), "hello ");
+        let mut buf = [0u8; 16];
+        assert_eq!(io.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        io.set_time(Time::zero() + Duration::from_millis(150));
+        assert_eq!(io.read(&mut buf).unwrap(), 6);
+        io.set_time(Time::zero() + Duration::from_millis(200));
+        assert_eq!(io.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+    }
+
+    #[test]
+    fn loopback() {
+        let mut io = MemIo::loopback();
+        assert_eq!(io.write(b"ping").unwrap(), 4);
+        let mut buf = [0u8; 16];
+        assert_eq!(io.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+        assert_eq!(io.output_str(), "ping");
+        io.shutdown_output();
+        assert_eq!(io.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn pair_closed_input() {
+        use std::net::Shutdown;
+
+        let (mut a, mut b) = MemIo::pair();
+        b.shutdown_input();
+        assert_eq!(a.write(b"ping").unwrap(), 4);
+        assert_eq!(a.output_str(), "ping");
+        let mut buf = [0u8; 16];
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        let mut io = MemIo::loopback();
+        io.shutdown(Shutdown::Read).unwrap();
+        assert_eq!(io.write(b"ping").unwrap(), 4);
+        assert_eq!(io.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn loopback_reset() {
+        use std::sync::{Arc, Mutex};
+
+        let mut io = MemIo::loopback();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let events = seen.clone();
+        io.set_observer(move |e| events.lock().unwrap().push(e.clone()));
+        io.reset_connection();
+        assert_eq!(io.timeline(), vec![IoEvent::Reset]);
+        assert_eq!(*seen.lock().unwrap(), vec![IoEvent::Reset]);
+        let mut buf = [0u8; 16];
+        assert_eq!(io.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn app_shutdown() {
+        use std::net::Shutdown;
+
+        let io = MemIo::new();
+        assert!(!io.is_output_closed());
+        io.shutdown(Shutdown::Write).unwrap();
+        assert!(io.is_output_closed());
+        assert!(!io.is_input_closed());
+        assert_eq!(io.shutdown(Shutdown::Write).unwrap_err().kind(),
+                   ErrorKind::NotConnected);
+        io.shutdown(Shutdown::Both).unwrap();
+        assert!(io.is_input_closed());
+        assert_eq!(io.timeline(),
+                   vec![IoEvent::ShutdownOutput, IoEvent::ShutdownInput]);
+    }
+
+    #[test]
+    fn observer() {
+        use std::sync::{Arc, Mutex};
+
+        let mut io = MemIo::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let ev = events.clone();
+        let stream = io.clone();
+        io.set_observer(move |e| {
+            // The stream is not locked while the observer is called
+            ev.lock().unwrap().push((e.clone(), stream.output_str()));
+        });
+        assert_eq!(io.write(b"hello").unwrap(), 5);
+        io.flush().unwrap();
+        io.shutdown_input();
+        assert_eq!(*events.lock().unwrap(), vec![
+            (IoEvent::Write(b"hello".to_vec()), "hello".to_string()),
+            (IoEvent::Flush, "hello".to_string()),
+            (IoEvent::ShutdownInput, "hello".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn output_since() {
+        let mut io = MemIo::new();
+        assert_eq!(io.write(b"first").unwrap(), 5);
+        let mark = io.mark();
+        assert_eq!(io.write(b"second").unwrap(), 6);
+        assert_eq!(io.output_since(mark), b"second");
+        io.consume_output(7);
+        assert_eq!(io.output_since(mark), b"cond");
+        assert_eq!(io.output_since(io.mark()), b"");
+    }
+
+    #[test]
+    fn would_block_next() {
+        let mut io = MemIo::new();
+        io.push_bytes("data");
+        io.would_block_next(3);
+        let mut buf = [0u8; 16];
+        for _ in 0..2 {
+            assert_eq!(io.read(&mut buf).unwrap_err().kind(),
+                       ErrorKind::WouldBlock);
+        }
+        assert_eq!(io.write(b"x").unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        assert_eq!(io.read(&mut buf).unwrap(), 4);
+        assert_eq!(io.write(b"x").unwrap(), 1);
+    }
+
+    #[test]
+    fn reuse_after_reset() {
+        let mut io = MemIo::new();
+        io.push_bytes("data");
+        assert_eq!(io.write(b"output").unwrap(), 6);
+        io.shutdown_input();
+        io.reset_connection();
+        io.reset();
+        assert_eq!(io.output_str(), "");
+        assert_eq!(io.stats(), IoStats::default());
+        assert_eq!(io.timeline(), vec![]);
+        let mut buf = [0u8; 16];
+        assert_eq!(io.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        io.push_bytes("again");
+        assert_eq!(io.read(&mut buf).unwrap(), 5);
+        assert_eq!(io.write(b"ok").unwrap(), 2);
+    }
+
+    #[test]
+    fn reset_split_points() {
+        let mut io = MemIo::new();
+        io.set_split_points(&[3, 8]);
+        io.push_bytes("abcde");
+        let mut buf = [0u8; 16];
+        assert_eq!(io.read(&mut buf).unwrap(), 3);
+        io.fail_flush_permanently(Error::other("dead"));
+        io.reset();
+        io.push_bytes("0123456789");
+        assert_eq!(io.read(&mut buf).unwrap(), 10);
+        io.flush().unwrap();
+    }
+
+    #[test]
+    fn generated_input() {
+        let mut io = MemIo::new();
+        io.push_bytes("head:");
+        io.push_generated(1 << 24, |offset, buf| {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = b'a' + ((offset + i as u64) % 26) as u8;
+            }
+        });
+        io.push_bytes(":tail");
+        let mut buf = [0u8; 8];
+        assert_eq!(io.read(&mut buf).unwrap(), 5);
+        assert_eq!(io.peek(&mut buf).unwrap(), 8);
+        assert_eq!(&buf, b"abcdefgh");
+        let mut total = 0u64;
+        let mut big = vec![0u8; 1 << 20];
+        while total < 1 << 24 {
+            total += io.read(&mut big).unwrap() as u64;
+        }
+        assert_eq!(total, 1 << 24);
+        assert_eq!(&big[big.len()-2..], b"mn");
+        assert_eq!(io.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b":tail");
+    }
+
+    #[test]
+    fn line_buffered() {
+        let mut io = MemIo::new();
+        io.set_line_buffered(true);
+        io.push_bytes("first\nsec");
+        let mut buf = [0u8; 16];
+        assert_eq!(io.read(&mut buf).unwrap(), 6);
+        assert_eq!(io.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        io.push_bytes("ond\nthird");
+        assert_eq!(io.read(&mut buf).unwrap(), 7);
+        assert_eq!(&buf[..7], b"second\n");
+        io.shutdown_input();
+        assert_eq!(io.read(&mut buf).unwrap(), 5);
+        assert_eq!(io.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn split_points() {
+        let mut io = MemIo::new();
+        io.set_split_points(&[10, 5, 9]);
+        io.push_bytes("0123456789abcdef");
+        let mut buf = [0u8; 32];
+        let mut sizes = Vec::new();
+        while let Ok(n) = io.read(&mut buf) {
+            if n == 0 { break; }
+            sizes.push(n);
+        }
+        assert_eq!(sizes, vec![5, 4, 1, 6]);
+    }
+
+    #[test]
+    #[should_panic(expected="Output limit of the mock stream exceeded")]
+    fn output_limit() {
+        let mut io = MemIo::new();
+        io.set_output_limit(1000);
+        loop {
+            assert_eq!(io.write(b"runaway").unwrap(), 7);
+        }
+    }
+
+    #[test]
+    fn timed_timeline() {
+        use std::time::Duration;
+        use rotor::Time;
+
+        let mut io = MemIo::new();
+        io.push_bytes("request");
+        let mut buf = [0u8; 16];
+        assert_eq!(io.read(&mut buf).unwrap(), 7);
+        io.set_time(Time::zero() + Duration::from_secs(1));
+        assert_eq!(io.write(b"response").unwrap(), 8);
+        assert_eq!(io.timed_timeline(), vec![
+            (Time::zero(), IoEvent::Read(7)),
+            (Time::zero() + Duration::from_secs(1),
+             IoEvent::Write(b"response".to_vec())),
+        ]);
+    }
+
+    #[test]
+    fn debug_limit() {
+        let mut io = MemIo::new();
+        io.set_debug_limit(4, 3);
+        io.push_bytes("0123456789");
+        assert_eq!(io.write(b"short").unwrap(), 5);
+        assert_eq!(format!("{:?}", io),
+            "MemIo { input: \"0123\"... 3 bytes skipped ...\"789\", \
+             input_closed: false, output: \"short\", \
+             output_closed: false }");
+        assert!(format!("{:#?}", io).contains("... 3 bytes skipped ..."));
+    }
+
+    #[test]
+    fn output_str_strict() {
+        let mut io = MemIo::new();
+        assert_eq!(io.write(b"hello").unwrap(), 5);
+        assert_eq!(io.output_str_strict().unwrap(), "hello");
+        assert_eq!(io.write(b"\xff").unwrap(), 1);
+        assert!(io.output_str_strict().is_err());
+        assert_eq!(io.output_str(), "hello\u{fffd}");
+    }
+
+    #[test]
+    fn with_capacity() {
+        let mut io = MemIo::with_capacity(1024, 4096);
+        io.push_bytes("hello");
+        io.reserve_output(8192);
+        let mut buf = [0u8; 16];
+        assert_eq!(io.read(&mut buf).unwrap(), 5);
+        assert_eq!(io.write(&buf[..5]).unwrap(), 5);
+        assert_eq!(io.output_str(), "hello");
+    }
+
+    #[test]
+    fn netbuf() {
+        use rotor_stream::Buf;
+
+        let mut io = MemIo::new();
+        let mut buf = Buf::new();
+        buf.extend(b"hello");
+        io.push_buf(&buf);
+        let mut data = [0u8; 16];
+        let bytes = io.read(&mut data).unwrap();
+        assert_eq!(io.write(&data[..bytes]).unwrap(), bytes);
+        assert_eq!(&io.output_as_buf()[..], b"hello");
+    }
+
+    #[test]
+    fn handle_count() {
+        let io = MemIo::new();
+        let app = io.clone();
+        assert_eq!(io.handle_count(), 2);
+        drop(app);
+        io.assert_handle_count(1);
+    }
+
+    #[test]
+    fn short_writes() {
+        let mut s = MemIo::new();
+        s.set_write_limit(3);
+        assert_eq!(s.write(b"hello").unwrap(), 3);
+        assert_eq!(s.write(b"lo").unwrap(), 2);
+        assert_eq!(s.output_str(), "hello");
+    }
+
+    #[test]
+    fn output_capacity() {
+        let mut s = MemIo::new();
+        s.set_output_capacity(4);
+        assert_eq!(s.write(b"hello").unwrap(), 4);
+        assert_eq!(s.write(b"o").unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(s.output_str(), "hell");
+        s.consume_output(2);
+        assert_eq!(s.write(b"o").unwrap(), 1);
+        assert_eq!(s.output_str(), "llo");
+    }
+
+    #[test]
+    fn pair() {
+        let (mut a, mut b) = MemIo::pair();
+        assert_eq!(a.write(b"ping").unwrap(), 4);
+        assert_eq!(b.write(b"pong").unwrap(), 4);
+        let mut buf = [0u8; 16];
+        assert_eq!(b.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+        assert_eq!(a.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"pong");
+        assert_eq!(a.output_str(), "ping");
+    }
+
+    #[test]
+    fn scripted() {
+        let mut s = MemIo::scripted(vec![
+            IoStep::Read(b"hello".to_vec()),
+            IoStep::WouldBlock,
+            IoStep::Write(2),
+            IoStep::Error(ErrorKind::ConnectionReset),
+            IoStep::Eof,
+        ]);
+        let mut buf = [0u8; 3];
+        assert_eq!(s.read(&mut buf).unwrap(), 3);
+        assert_eq!(s.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(s.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        assert_eq!(s.write(b"world").unwrap(), 2);
+        assert_eq!(s.write(b"rld").unwrap_err().kind(),
+                   ErrorKind::ConnectionReset);
+        assert_eq!(s.read(&mut buf).unwrap(), 0);
+        assert_eq!(s.output_str(), "wo");
+    }
+
+    #[test]
+    fn stats() {
+        let mut s = MemIo::new();
+        s.push_bytes("hello");
+        let mut buf = [0u8; 16];
+        assert_eq!(s.read(&mut buf).unwrap(), 5);
+        s.read(&mut buf).unwrap_err();
+        assert_eq!(s.write(b"abc").unwrap(), 3);
+        assert_eq!(s.stats(), IoStats {
+            reads: 2,
+            writes: 1,
+            bytes_read: 5,
+            bytes_written: 3,
+            would_blocks: 1,
+            errors: 0,
+        });
+    }
+
+    #[test]
+    fn timeline() {
+        let mut s = MemIo::new();
+        s.push_bytes("req");
+        let mut buf = [0u8; 16];
+        assert_eq!(s.read(&mut buf).unwrap(), 3);
+        s.read(&mut buf).unwrap_err();
+        assert_eq!(s.write(b"resp").unwrap(), 4);
+        s.shutdown_input();
+        assert_eq!(s.timeline(), vec![
+            IoEvent::Read(3),
+            IoEvent::ReadError(ErrorKind::WouldBlock),
+            IoEvent::Write(b"resp".to_vec()),
+            IoEvent::ShutdownInput,
+        ]);
+    }
+
+    #[test]
+    fn shutdown_output() {
+        let (mut a, mut b) = MemIo::pair();
+        assert_eq!(a.write(b"bye").unwrap(), 3);
+        a.shutdown_output();
+        assert_eq!(a.write(b"more").unwrap_err().kind(),
+                   ErrorKind::BrokenPipe);
+        let mut data = String::new();
+        assert_eq!(b.read_to_string(&mut data).unwrap(), 3);
+        assert_eq!(data, "bye");
+    }
+
+    #[test]
+    fn reset() {
+        let mut s = MemIo::new();
+        s.push_bytes("unread");
+        s.reset_connection();
+        let mut buf = [0u8; 16];
+        assert_eq!(s.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::ConnectionReset);
+        assert_eq!(s.write(b"x").unwrap_err().kind(),
+                   ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn flush_errors() {
+        let mut s = MemIo::new();
+        s.fail_flush(Error::other("once"));
+        assert_eq!(s.flush().unwrap_err().kind(), ErrorKind::Other);
+        s.flush().unwrap();
+        s.fail_flush_permanently(Error::new(ErrorKind::BrokenPipe, "dead"));
+        assert_eq!(s.flush().unwrap_err().kind(), ErrorKind::BrokenPipe);
+        assert_eq!(s.flush().unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn vectored() {
+        let mut s = MemIo::new();
+        s.push_bytes("hello world");
+        let mut a = [0u8; 5];
+        let mut b = [0u8; 10];
+        assert_eq!(s.readv(&mut [&mut a[..], &mut b[..]]).unwrap(), 11);
+        assert_eq!(&a, b"hello");
+        assert_eq!(&b[..6], b" world");
+        assert_eq!(s.writev(&[b"GET ", b"/ ", b"HTTP/1.1"]).unwrap(), 14);
+        assert_eq!(s.writev(&[b"\r\n"]).unwrap(), 2);
+        assert_eq!(s.output_str(), "GET / HTTP/1.1\r\n");
+        assert_eq!(s.readv_buffer_counts(), vec![2]);
+        assert_eq!(s.writev_buffer_counts(), vec![3, 1]);
+        assert_eq!(s.stats().writes, 2);
+    }
+
+    #[test]
+    fn peek() {
+        let mut s = MemIo::new();
+        let mut buf = [0u8; 4];
+        assert_eq!(s.peek(&mut buf).unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        s.push_bytes("GET /");
+        assert_eq!(s.peek(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"GET ");
+        let mut data = [0u8; 16];
+        assert_eq!(s.read(&mut data).unwrap(), 5);
+    }
+
+    #[test]
+    fn peek_chunks() {
+        let mut s = MemIo::new();
+        s.set_line_buffered(true);
+        s.set_split_points(&[2]);
+        s.set_random_chunking(7);
+        s.push_bytes("GET /\nHost: x\n");
+        let mut peeked = [0u8; 16];
+        let mut data = [0u8; 16];
+        loop {
+            let bytes = match s.peek(&mut peeked) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    assert_eq!(e.kind(), ErrorKind::WouldBlock);
+                    break;
+                }
+            };
+            assert_eq!(s.read(&mut data).unwrap(), bytes);
+            assert_eq!(&data[..bytes], &peeked[..bytes]);
+        }
+        assert_eq!(s.stats().bytes_read, 14);
+    }
+
+    #[test]
+    fn hexdump() {
+        let mut s = MemIo::new();
+        s.push_bytes(b"hello\x00\xff");
+        let dump = format!("{:#?}", s);
+        assert!(dump.contains("00000000  68 65 6c 6c 6f 00 ff "), "{}", dump);
+        assert!(dump.contains(" |hello..|"), "{}", dump);
+        assert!(dump.contains("output: []"), "{}", dump);
+    }
+
+    #[test]
+    fn errors() {
+        let mut s = MemIo::new();
+        s.push_bytes("hello");
+        s.push_read_error(Error::new(ErrorKind::ConnectionReset, "reset"));
+        s.push_write_error(Error::new(ErrorKind::BrokenPipe, "pipe"));
+        let mut buf = [0u8; 16];
+        assert_eq!(s.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::ConnectionReset);
+        assert_eq!(s.read(&mut buf).unwrap(), 5);
+        assert_eq!(s.write(b"hello").unwrap_err().kind(),
+                   ErrorKind::BrokenPipe);
+        assert_eq!(s.write(b"hello").unwrap(), 5);
+        assert_eq!(s.output_str(), "hello");
+    }
+
 }
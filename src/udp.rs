@@ -0,0 +1,182 @@
+use std::io;
+use std::cmp::min;
+use std::net::SocketAddr;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rotor::mio;
+
+use scope::Registration;
+
+
+/// In memory datagram socket
+///
+/// Has `send_to` and `recv_from` methods with the same signatures as
+/// `mio::udp::UdpSocket`, so a machine which is generic over the socket
+/// (or uses a type alias in tests) can be tested without the network.
+///
+/// Message boundaries are preserved: each `recv_from` returns a single
+/// datagram pushed by `push_datagram`, and each `send_to` is recorded as
+/// a separate entry in `sent_datagrams()`.
+///
+/// Like `MemIo` it implements `mio::Evented` by recording the registration
+/// (see `registration()`). When registered through `MockLoop` the token is
+/// marked readable while there are pending datagrams. Clone the socket to
+/// keep a handle for the test.
+#[derive(Clone, Debug)]
+pub struct MockUdp(Arc<Mutex<Datagrams>>);
+
+#[derive(Debug)]
+struct Datagrams {
+    input: VecDeque<(Vec<u8>, SocketAddr)>,
+    sent: Vec<(Vec<u8>, SocketAddr)>,
+    registration: Registration,
+}
+
+impl MockUdp {
+    /// Create a socket with no pending datagrams
+    pub fn new() -> MockUdp {
+        MockUdp(Arc::new(Mutex::new(Datagrams {
+            input: VecDeque::new(),
+            sent: Vec::new(),
+            registration: Registration::default(),
+        })))
+    }
+    /// Push a datagram from `source` for the next `recv_from`
+    pub fn push_datagram<T: AsRef<[u8]>>(&self, data: T, source: SocketAddr) {
+        let mut bufs = self.bufs();
+        bufs.input.push_back((data.as_ref().to_vec(), source));
+        bufs.signal_readable();
+    }
+    /// Number of datagrams which are not received by the application yet
+    pub fn pending_datagrams(&self) -> usize {
+        self.bufs().input.len()
+    }
+    /// Get all datagrams sent by the application with their destinations
+    pub fn sent_datagrams(&self) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.bufs().sent.clone()
+    }
+    /// Same as `sent_datagrams` but also clears the list
+    pub fn take_sent_datagrams(&self) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.bufs().sent.drain(..).collect()
+    }
+    /// Record a datagram sent to `target`
+    ///
+    /// The whole datagram is always sent.
+    pub fn send_to(&self, buf: &[u8], target: &SocketAddr)
+        -> io::Result<Option<usize>>
+    {
+        self.bufs().sent.push((buf.to_vec(), *target));
+        Ok(Some(buf.len()))
+    }
+    /// Receive next pushed datagram, `None` means it would block
+    ///
+    /// Like with real sockets, if the datagram doesn't fit the buffer the
+    /// rest of it is discarded.
+    pub fn recv_from(&self, buf: &mut [u8])
+        -> io::Result<Option<(usize, SocketAddr)>>
+    {
+        Ok(self.bufs().input.pop_front().map(|(data, source)| {
+            let bytes = min(buf.len(), data.len());
+            buf[..bytes].copy_from_slice(&data[..bytes]);
+            (bytes, source)
+        }))
+    }
+    /// Returns token, interest and poll options the socket is registered
+    /// with in the loop, if any
+    pub fn registration(&self)
+        -> Option<(mio::Token, mio::EventSet, mio::PollOpt)>
+    {
+        self.bufs().registration.get()
+    }
+    fn bufs<'a>(&'a self) -> MutexGuard<'a, Datagrams> {
+        self.0.lock().expect("Poisoned MockUdp (mock socket)")
+    }
+}
+
+impl Default for MockUdp {
+    fn default() -> MockUdp {
+        MockUdp::new()
+    }
+}
+
+impl Datagrams {
+    fn signal_readable(&self) {
+        if !self.input.is_empty() {
+            self.registration.signal(mio::EventSet::readable());
+        }
+    }
+}
+
+impl mio::Evented for MockUdp {
+    fn register(&self, _selector: &mut mio::Selector,
+        token: mio::Token, interest: mio::EventSet, opts: mio::PollOpt)
+        -> io::Result<()>
+    {
+        let mut bufs = self.bufs();
+        bufs.registration.register(token, interest, opts)?;
+        bufs.signal_readable();
+        Ok(())
+    }
+    fn reregister(&self, _selector: &mut mio::Selector, token: mio::Token,
+        interest: mio::EventSet, opts: mio::PollOpt) -> io::Result<()>
+    {
+        let mut bufs = self.bufs();
+        bufs.registration.reregister(token, interest, opts)?;
+        bufs.signal_readable();
+        Ok(())
+    }
+    fn deregister(&self, _selector: &mut mio::Selector) -> io::Result<()>
+    {
+        self.bufs().registration.deregister()
+    }
+}
+
+#[cfg(test)]
+mod self_test {
+    use std::net::SocketAddr;
+    use rotor::{EventSet, PollOpt};
+    use rotor::mio::Token;
+    use scope::MockLoop;
+    use super::MockUdp;
+
+    #[test]
+    fn datagrams() {
+        let a: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:53".parse().unwrap();
+        let sock = MockUdp::new();
+        sock.push_datagram("hello", a);
+        sock.push_datagram("world", b);
+        let mut buf = [0u8; 3];
+        assert_eq!(sock.recv_from(&mut buf).unwrap(), Some((3, a)));
+        assert_eq!(&buf, b"hel");
+        assert_eq!(sock.recv_from(&mut buf).unwrap(), Some((3, b)));
+        assert_eq!(sock.recv_from(&mut buf).unwrap(), None);
+        sock.send_to(b"one", &a).unwrap();
+        sock.send_to(b"two", &b).unwrap();
+        assert_eq!(sock.take_sent_datagrams(),
+            vec![(b"one".to_vec(), a), (b"two".to_vec(), b)]);
+        assert_eq!(sock.sent_datagrams(), vec![]);
+    }
+
+    #[test]
+    fn register() {
+        let a: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let mut factory = MockLoop::new(());
+        let sock = MockUdp::new();
+        factory.scope(4).register(&sock, EventSet::readable(),
+            PollOpt::edge()).unwrap();
+        assert_eq!(sock.registration(),
+            Some((Token(4), EventSet::readable(), PollOpt::edge())));
+        assert_eq!(factory.ready_tokens(), vec![]);
+        sock.push_datagram("hello", a);
+        assert_eq!(factory.take_ready_tokens(),
+                   vec![(Token(4), EventSet::readable())]);
+        factory.scope(4).reregister(&sock, EventSet::writable(),
+            PollOpt::edge()).unwrap();
+        assert_eq!(factory.ready_tokens(), vec![]);
+        factory.scope(4).deregister(&sock).unwrap();
+        assert!(factory.scope(4).reregister(&sock, EventSet::readable(),
+            PollOpt::edge()).is_err());
+    }
+}